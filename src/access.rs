@@ -0,0 +1,257 @@
+//! Access control for the notify endpoint
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	task::{Context, Poll},
+};
+
+use axum::{
+	body::Body,
+	extract::ConnectInfo,
+	http::{header::AUTHORIZATION, HeaderMap, Request},
+	response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use opentelemetry::{metrics::Counter, KeyValue};
+use tower::Service;
+use tracing::warn;
+
+use crate::{
+	error::{ErrCode, HedwigError},
+	models::Metrics,
+	reload::LiveConfig,
+	settings::{Access, HomeserverAccess},
+};
+
+/// Client certificate a connection was authenticated with
+///
+/// Inserted into the request extensions by the TLS listener when mutual TLS is
+/// used.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+	/// Hex encoded SHA-256 fingerprint of the DER encoded certificate
+	pub fingerprint: String,
+}
+
+/// The homeserver a request was authenticated as
+#[derive(Debug, Clone)]
+pub struct AuthenticatedHomeserver {
+	/// Name of the homeserver as configured
+	pub name: String,
+	/// Whether the homeserver may see the per-device delivery outcomes
+	pub trusted: bool,
+}
+
+/// Reason a request was rejected by the access control
+#[derive(Debug, Clone, Copy)]
+enum Denial {
+	/// The peer address is not in any of the allowed networks
+	Network,
+	/// The request did not carry any credentials
+	MissingCredentials,
+	/// The credentials did not match any configured homeserver
+	InvalidCredentials,
+}
+
+impl Denial {
+	/// Label used for logs and metrics
+	const fn as_str(self) -> &'static str {
+		match self {
+			Self::Network => "network",
+			Self::MissingCredentials => "missing_credentials",
+			Self::InvalidCredentials => "invalid_credentials",
+		}
+	}
+
+	/// Error returned to the client
+	fn into_error(self) -> HedwigError {
+		match self {
+			Self::Network => {
+				HedwigError { error: "Address not allowed".to_owned(), errcode: ErrCode::Forbidden }
+			}
+			Self::MissingCredentials => HedwigError {
+				error: "Missing credentials".to_owned(),
+				errcode: ErrCode::Unauthorized,
+			},
+			Self::InvalidCredentials => {
+				HedwigError { error: "Invalid credentials".to_owned(), errcode: ErrCode::Forbidden }
+			}
+		}
+	}
+}
+
+/// Compares two byte strings without exiting early on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Normalises a certificate fingerprint to lowercase hex without separators
+fn normalize_fingerprint(fingerprint: &str) -> String {
+	fingerprint.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+impl HomeserverAccess {
+	/// Whether the given bearer token or client certificate belongs to this
+	/// homeserver
+	fn matches(&self, token: Option<&str>, certificate: Option<&ClientCertificate>) -> bool {
+		let token_matches = self.token.as_ref().zip(token).is_some_and(|(expected, given)| {
+			constant_time_eq(expected.as_bytes(), given.as_bytes())
+		});
+
+		let certificate_matches = certificate.is_some_and(|certificate| {
+			let fingerprint = normalize_fingerprint(&certificate.fingerprint);
+			self.client_certificate_fingerprints
+				.iter()
+				.any(|expected| normalize_fingerprint(expected) == fingerprint)
+		});
+
+		token_matches || certificate_matches
+	}
+}
+
+/// Checks a request against the access configuration
+///
+/// Returns the homeserver the request authenticated as (if any) and the reason
+/// it has to be rejected (if any).
+fn authorize<'a>(
+	access: &'a Access,
+	req: &Request<Body>,
+) -> (Option<&'a HomeserverAccess>, Option<Denial>) {
+	let token = bearer_token(req.headers());
+	let certificate = req.extensions().get::<ClientCertificate>();
+
+	let homeserver = access.homeservers.iter().find(|hs| hs.matches(token, certificate));
+
+	let peer_allowed = access.allowed_networks.is_empty()
+		|| peer_ip(req)
+			.is_some_and(|ip| access.allowed_networks.iter().any(|net| net.contains(&ip)));
+
+	let denial = if !peer_allowed {
+		Some(Denial::Network)
+	} else if access.homeservers.is_empty() || homeserver.is_some() {
+		None
+	} else if token.is_none() && certificate.is_none() {
+		Some(Denial::MissingCredentials)
+	} else {
+		Some(Denial::InvalidCredentials)
+	};
+
+	(homeserver, denial)
+}
+
+/// Token from the `Authorization: Bearer` header, if any
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+	headers
+		.get(AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Address of the peer that sent the request
+fn peer_ip(req: &Request<Body>) -> Option<IpAddr> {
+	req.extensions()
+		.get::<ConnectInfo<SocketAddr>>()
+		.map(|ConnectInfo(addr)| addr.ip().to_canonical())
+}
+
+/// Middleware rejecting requests that don't pass the configured access control
+#[derive(Debug, Clone)]
+pub struct AccessControlLayer {
+	/// Hedwig settings holding the access configuration
+	config: LiveConfig,
+	/// Counter for rejected requests
+	access_denied: Counter<u64>,
+}
+
+impl AccessControlLayer {
+	/// Create a new access control middleware
+	#[must_use]
+	pub fn new(config: LiveConfig, metrics: &Metrics) -> Self {
+		Self { config, access_denied: metrics.access_denied.clone() }
+	}
+}
+
+impl<S> tower::Layer<S> for AccessControlLayer {
+	type Service = AccessControlService<S>;
+
+	fn layer(&self, inner: S) -> Self::Service {
+		AccessControlService {
+			inner,
+			config: self.config.clone(),
+			access_denied: self.access_denied.clone(),
+		}
+	}
+}
+
+/// Service implementation for the access control middleware
+#[derive(Debug, Clone)]
+pub struct AccessControlService<S> {
+	/// Inner service
+	inner: S,
+	/// Hedwig settings holding the access configuration
+	config: LiveConfig,
+	/// Counter for rejected requests
+	access_denied: Counter<u64>,
+}
+
+impl<S> Service<Request<Body>> for AccessControlService<S>
+where
+	S: Service<Request<Body>, Response = Response> + Send + 'static,
+	S::Future: Send + 'static,
+{
+	type Response = S::Response;
+	type Error = S::Error;
+	type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+		let config = self.config.current();
+		let (homeserver, denial) = authorize(&config.settings.server.access, &req);
+		let homeserver = homeserver.map(|hs| (hs.name.clone(), hs.trusted));
+
+		if let Some(denial) = denial {
+			let homeserver = homeserver.map_or_else(|| "unknown".to_owned(), |(name, _)| name);
+			warn!(
+				"Rejected push request (homeserver: {}, peer: {:?}): {}",
+				homeserver,
+				peer_ip(&req),
+				denial.as_str()
+			);
+			self.access_denied.add(
+				1,
+				&[
+					KeyValue::new("homeserver", homeserver),
+					KeyValue::new("reason", denial.as_str()),
+				],
+			);
+			return Box::pin(async move { Ok(denial.into_error().into_response()) });
+		}
+
+		if let Some((name, trusted)) = homeserver {
+			req.extensions_mut().insert(AuthenticatedHomeserver { name, trusted });
+		}
+
+		Box::pin(self.inner.call(req))
+	}
+}
@@ -0,0 +1,150 @@
+//! Admin API for inspecting a running Hedwig
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use axum::{
+	extract::{Request, State},
+	middleware::{self, Next},
+	response::{IntoResponse, Response},
+	routing::{get, post},
+	Json, Router,
+};
+use color_eyre::{eyre::WrapErr, Report};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use crate::{
+	access::{bearer_token, constant_time_eq},
+	api::AppState,
+	error::{ErrCode, HedwigError},
+	health::ComponentStatus,
+	models::{Device, Notification, ProviderResponse},
+	pusher,
+	settings::Admin,
+};
+
+/// State of the admin router
+#[derive(Debug, Clone)]
+pub struct AdminState {
+	/// State shared with the push gateway
+	app_state: AppState,
+}
+
+/// Rejects requests that don't carry the configured admin token
+async fn require_token(State(state): State<AdminState>, req: Request, next: Next) -> Response {
+	let config = state.app_state.config().current();
+	let Some(token) = bearer_token(req.headers()) else {
+		warn!("Rejected admin request to {} without a token", req.uri().path());
+		return HedwigError {
+			error: "Missing admin token".to_owned(),
+			errcode: ErrCode::Unauthorized,
+		}
+		.into_response();
+	};
+
+	let authorized = config
+		.settings
+		.server
+		.admin
+		.as_ref()
+		.is_some_and(|admin| constant_time_eq(admin.token.as_bytes(), token.as_bytes()));
+	if !authorized {
+		warn!("Rejected admin request to {}", req.uri().path());
+		return HedwigError {
+			error: "Invalid admin token".to_owned(),
+			errcode: ErrCode::UnknownToken,
+		}
+		.into_response();
+	}
+
+	next.run(req).await
+}
+
+/// Push a test notification to the given device, returning what the push
+/// service answered
+async fn test_push(
+	State(state): State<AdminState>,
+	Json(device): Json<Device>,
+) -> Result<Json<ProviderResponse>, HedwigError> {
+	let config = state.app_state.config().current();
+	let notification = Notification::test(device.clone());
+
+	info!("Sending test push through the admin API");
+	Ok(Json(
+		pusher::push_notification(&notification, &device, &config, state.app_state.counters())
+			.await?,
+	))
+}
+
+/// Config the current settings were loaded from, with secrets redacted
+#[allow(clippy::unused_async)]
+async fn config(State(state): State<AdminState>) -> Result<Json<Value>, HedwigError> {
+	let config = state.app_state.config().current();
+	if config.settings.redacted.is_null() {
+		return Err(HedwigError {
+			error: "Hedwig was not started from a config file".to_owned(),
+			errcode: ErrCode::ConfigFailed,
+		});
+	}
+
+	Ok(Json(config.settings.redacted.clone()))
+}
+
+/// Check the push services now and show whether they can be authenticated
+/// with
+async fn senders(State(state): State<AdminState>) -> Json<BTreeMap<&'static str, ComponentStatus>> {
+	state.app_state.run_checks().await;
+	Json(state.app_state.health().components().await)
+}
+
+/// Create the admin router
+#[must_use]
+pub fn create_admin_router(app_state: AppState) -> Router {
+	let state = AdminState { app_state };
+
+	Router::new()
+		.route("/admin/test-push", post(test_push))
+		.route("/admin/config", get(config))
+		.route("/admin/senders", get(senders))
+		.layer(middleware::from_fn_with_state(state.clone(), require_token))
+		.with_state(state)
+}
+
+/// Bind the admin API and serve it in the background until shutdown
+pub async fn spawn_server(admin: &Admin, app_state: AppState) -> Result<(), Report> {
+	let addr: SocketAddr = (admin.bind_address, admin.port).into();
+	let listener =
+		tokio::net::TcpListener::bind(&addr).await.wrap_err("Failed to bind admin API")?;
+
+	let shutdown = app_state.shutdown().clone();
+	let router = create_admin_router(app_state);
+
+	info!("Serving admin API on {}", addr);
+	tokio::spawn(async move {
+		let server = axum::serve(listener, router)
+			.with_graceful_shutdown(async move { shutdown.triggered().await });
+		if let Err(e) = server.await {
+			error!("Admin API failed: {}", e);
+		}
+	});
+
+	Ok(())
+}
@@ -18,40 +18,240 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+	future::IntoFuture,
+	net::SocketAddr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 use axum::{
 	extract::{DefaultBodyLimit, FromRef, State},
+	http::StatusCode,
 	response::Redirect,
 	routing::{get, post},
-	Json, Router,
+	Extension, Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
 use color_eyre::{eyre::WrapErr, Report};
 use opentelemetry::{metrics::MeterProvider, KeyValue};
 use opentelemetry_sdk::{metrics::SdkMeterProvider, Resource};
-use tokio::sync::Mutex;
 use tower_http::{catch_panic::CatchPanicLayer, normalize_path::NormalizePathLayer};
-use tracing::{debug, info, instrument};
+use tracing::{debug, field::Empty, info, info_span, instrument, warn, Instrument, Span};
 
 use crate::{
+	access::{AccessControlLayer, AuthenticatedHomeserver},
+	admin,
 	apns::APNSSender,
+	error::{self, ErrCode, HedwigError},
 	fcm::FcmSender,
+	health::{self, Health, Readiness},
 	metrics::{metrics_handler, HttpMetricsMiddleware},
-	models::{Metrics, Notification, NotificationMethod, PushGatewayResponse},
+	models::{
+		hash_pushkey, DeliveryOutcome, DeliveryStatus, Device, Metrics, Notification,
+		PushGatewayResponse,
+	},
 	pusher,
-	settings::Settings,
+	reload::{self, LiveConfig, Snapshot},
+	settings::{Hedwig, LegacyDataMessage, Privacy, Settings},
+	shutdown::{self, Shutdown},
+	tls::{self, ClientCertificateAcceptor},
 };
 
+/// Record as much about the notification in the span as the privacy level
+/// allows
+fn record_notification(span: &Span, notification: &Notification, privacy: Privacy) {
+	if privacy >= Privacy::Standard {
+		span.record("event_id", notification.event_id.as_deref());
+		span.record("notification_type", notification.r#type.as_deref());
+		span.record("prio", notification.prio.as_ref().map(tracing::field::debug));
+	}
+
+	if privacy >= Privacy::Full {
+		span.record("room_id", notification.room_id.as_deref());
+		span.record("sender", notification.sender.as_deref());
+		span.record(
+			"pushkeys",
+			notification
+				.devices
+				.iter()
+				.map(|device| hash_pushkey(&device.pushkey))
+				.collect::<Vec<_>>()
+				.join(",")
+				.as_str(),
+		);
+	}
+}
+
+/// Record the device a push goes to in the span as far as the privacy level
+/// allows
+fn record_device(span: &Span, device: &Device, privacy: Privacy) {
+	if privacy >= Privacy::Standard {
+		span.record("app_id", device.app_id.as_str());
+	}
+
+	if privacy >= Privacy::Full {
+		span.record("pushkey", hash_pushkey(&device.pushkey).as_str());
+	}
+}
+
+/// Push the notification to a single device, retrying with exponential backoff
+async fn deliver(
+	notification: &Notification,
+	device: &Device,
+	config: &Snapshot,
+	metrics: &Metrics,
+) -> DeliveryOutcome {
+	let started = Instant::now();
+	let transport = device.notify_via.clone().unwrap_or_default();
+	let mut retry_time = Duration::from_millis(250);
+	let mut attempts: u32 = 0;
+
+	let status = loop {
+		attempts += 1;
+		let span = info_span!(
+			"push.attempt",
+			transport = transport.as_str(),
+			app_id = Empty,
+			attempt = attempts,
+			pushkey = Empty,
+		);
+		record_device(&span, device, config.settings.log.privacy);
+		match pusher::push_notification(notification, device, config, metrics)
+			.instrument(span)
+			.await
+		{
+			Ok(response) => break DeliveryStatus::Delivered { message_id: response.message_id() },
+			Err(e) if i64::from(attempts) > config.settings.hedwig.push_max_retries => {
+				break DeliveryStatus::Failed { errcode: e.errcode, error: e.error };
+			}
+			Err(e) => {
+				debug!("A push failed, retrying in a bit. (Error: {})", e);
+
+				tokio::time::sleep(retry_time)
+					.instrument(info_span!(
+						"push.retry_wait",
+						attempt = attempts,
+						delay_ms = u64::try_from(retry_time.as_millis()).unwrap_or(u64::MAX),
+					))
+					.await;
+				retry_time *= 2;
+			}
+		}
+	};
+
+	metrics
+		.push_attempts
+		.record(u64::from(attempts), &[KeyValue::new("transport", transport.as_str())]);
+
+	DeliveryOutcome {
+		pushkey: device.pushkey.clone(),
+		transport,
+		attempts,
+		latency_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+		status,
+	}
+}
+
+/// Count devices using the deprecated `.data_message` app_id suffix and warn
+/// about or reject them as configured
+///
+/// Only app_ids with the configured prefix are counted, so requests can't
+/// create arbitrary metric labels. The others are rejected when pushing.
+fn check_legacy_app_id(
+	device: &Device,
+	settings: &Hedwig,
+	metrics: &Metrics,
+) -> Result<(), HedwigError> {
+	if !device.is_legacy_data_message() {
+		return Ok(());
+	}
+
+	if device.app_id.starts_with(&settings.app_id) {
+		metrics.legacy_devices.add(1, &[KeyValue::new("app_id", device.app_id.clone())]);
+	}
+
+	match settings.legacy_data_message {
+		LegacyDataMessage::Accept => Ok(()),
+		LegacyDataMessage::Warn => {
+			warn!(
+				app_id = device.app_id.as_str(),
+				"Device uses the deprecated .data_message app_id suffix"
+			);
+			Ok(())
+		}
+		LegacyDataMessage::Reject => Err(HedwigError {
+			error: "The .data_message app_id suffix is no longer supported".to_owned(),
+			errcode: ErrCode::BadJson,
+		}),
+	}
+}
+
+/// Emit the outcome of a push as a structured event, with the hashed pushkey
+/// only at the full privacy level
+fn log_outcome(outcome: &DeliveryOutcome, device_type: &str, privacy: Privacy) {
+	let pushkey = (privacy >= Privacy::Full).then(|| hash_pushkey(&outcome.pushkey));
+	match &outcome.status {
+		DeliveryStatus::Delivered { message_id } => debug!(
+			pushkey = pushkey.as_deref(),
+			device_type,
+			transport = ?outcome.transport,
+			attempts = outcome.attempts,
+			latency_ms = outcome.latency_ms,
+			message_id = message_id.as_deref(),
+			"Push delivered"
+		),
+		DeliveryStatus::Failed { errcode, error } => info!(
+			pushkey = pushkey.as_deref(),
+			device_type,
+			transport = ?outcome.transport,
+			attempts = outcome.attempts,
+			latency_ms = outcome.latency_ms,
+			?errcode,
+			error = error.as_str(),
+			"A push failed, even after retrying"
+		),
+	}
+}
+
 /// Endpoint for matrix push
-#[instrument]
+#[instrument(
+	skip_all,
+	fields(
+		devices = notification.devices.len(),
+		homeserver = homeserver.as_ref().map(|Extension(homeserver)| homeserver.name.as_str()),
+		event_id = Empty,
+		notification_type = Empty,
+		prio = Empty,
+		room_id = Empty,
+		sender = Empty,
+		pushkeys = Empty,
+	)
+)]
 pub async fn matrix_push(
 	State(app_state): State<AppState>,
+	homeserver: Option<Extension<AuthenticatedHomeserver>>,
 	notification: Notification,
-) -> Json<PushGatewayResponse> {
+) -> Result<Json<PushGatewayResponse>, HedwigError> {
 	let mut rejected: Vec<String> = Vec::new();
+	let mut delivered: usize = 0;
+	let mut server_failures: usize = 0;
+	let mut server_error: Option<HedwigError> = None;
+	let trusted = homeserver.as_ref().is_some_and(|Extension(homeserver)| homeserver.trusted);
+	let config = app_state.config.current();
+	record_notification(&Span::current(), &notification, config.settings.log.privacy);
 
-	debug!("Got notification to be pushed to {} devices.", notification.devices.len());
+	if let Some(Extension(homeserver)) = homeserver {
+		debug!(
+			"Got notification from {} to be pushed to {} devices.",
+			homeserver.name,
+			notification.devices.len()
+		);
+	} else {
+		debug!("Got notification to be pushed to {} devices.", notification.devices.len());
+	}
+	let mut outcomes = Vec::with_capacity(notification.devices.len());
 	for dev in &notification.devices {
 		let device_type = if dev.app_id.ends_with(".data_message") {
 			"AndroidLegacy".to_owned()
@@ -59,65 +259,49 @@ pub async fn matrix_push(
 			format!("{:?}", dev.data_message_type())
 		};
 
-		let mut retry_time = Duration::from_millis(250);
-		let mut attempt = 0;
-		let notify_via = dev.notify_via.clone().unwrap_or_default();
-		loop {
-			if let Err(e) = match notify_via {
-				NotificationMethod::Apns => {
-					if let Some(apns_sender) = &app_state.apns_sender {
-						pusher::push_notification_apns(
-							&notification,
-							dev,
-							apns_sender,
-							&app_state.settings,
-						)
-						.await
-					} else {
-						Err(crate::error::HedwigError {
-							error: "APNS sender not configured".to_owned(),
-							errcode: crate::error::ErrCode::APNSNotConfigured,
-						})
-					}
-				}
-				NotificationMethod::Fcm => {
-					pusher::push_notification_fcm(
-						&notification,
-						dev,
-						&app_state.fcm_sender,
-						&app_state.settings,
-					)
-					.await
-				}
-			} {
-				attempt += 1;
-				if attempt > app_state.settings.hedwig.push_max_retries {
-					info!(
-						"A push failed (device type: {}), even after retrying: {}",
-						device_type, e
-					);
-					app_state
-						.counters
-						.failed_pushes
-						.add(1, &[KeyValue::new("device_type", device_type.clone())]);
-					rejected.push(dev.pushkey.clone());
-					break;
-				}
-				debug!("A push failed, retrying in a bit. (Error: {})", e);
+		let outcome = match check_legacy_app_id(dev, &config.settings.hedwig, &app_state.counters) {
+			Ok(()) => deliver(&notification, dev, &config, &app_state.counters).await,
+			Err(e) => DeliveryOutcome {
+				pushkey: dev.pushkey.clone(),
+				transport: dev.notify_via.clone().unwrap_or_default(),
+				attempts: 0,
+				latency_ms: 0,
+				status: DeliveryStatus::Failed { errcode: e.errcode, error: e.error },
+			},
+		};
+		log_outcome(&outcome, &device_type, config.settings.log.privacy);
 
-				tokio::time::sleep(retry_time).await;
-				retry_time *= 2;
-			} else {
+		let counter = match &outcome.status {
+			DeliveryStatus::Delivered { .. } => {
+				delivered += 1;
+				&app_state.counters.successful_pushes
+			}
+			DeliveryStatus::Failed { errcode, .. } if errcode.rejects_pushkey() => {
+				rejected.push(dev.pushkey.clone());
 				app_state
 					.counters
-					.successful_pushes
-					.add(1, &[KeyValue::new("device_type", device_type.clone())]);
-				break;
+					.rejected_pushkeys
+					.add(1, &[KeyValue::new("reason", errcode.reason())]);
+				&app_state.counters.failed_pushes
 			}
-		}
+			DeliveryStatus::Failed { errcode, error } => {
+				// The homeserver removes rejected pushers, so outages must not
+				// end up in `rejected`
+				if errcode.status_code().is_server_error() {
+					server_failures += 1;
+					server_error.get_or_insert_with(|| HedwigError {
+						error: error.clone(),
+						errcode: *errcode,
+					});
+				}
+				&app_state.counters.failed_pushes
+			}
+		};
+		counter.add(1, &[KeyValue::new("device_type", device_type)]);
+		outcomes.push(outcome);
 	}
 
-	if rejected.len() < notification.devices.len() {
+	if delivered > 0 {
 		app_state.counters.notifications.add(
 			1,
 			[notification.r#type.map(|r#type| KeyValue::new("notification_type", r#type))]
@@ -130,7 +314,15 @@ pub async fn matrix_push(
 
 	app_state.counters.devices.add(notification.devices.len() as u64, &[]);
 
-	Json(PushGatewayResponse { rejected })
+	// Let the homeserver retry later if no device could be pushed to because
+	// of a server side fault
+	if server_failures > 0 && server_failures == notification.devices.len() {
+		if let Some(error) = server_error {
+			return Err(error);
+		}
+	}
+
+	Ok(Json(PushGatewayResponse { rejected, outcomes: trusted.then_some(outcomes) }))
 }
 
 /// Version of the crate
@@ -142,16 +334,14 @@ const VERSION: &str = match option_env!("VERGEN_GIT_SEMVER") {
 /// Struct holding shared state, settings and interfaces for the Hedwig router
 #[derive(Clone, FromRef, Debug)]
 pub struct AppState {
-	/// [FcmSender] for communication with Firebase
-	/// Usually [crate::fcm::FcmSenderImpl]
-	fcm_sender: Arc<Mutex<Box<dyn FcmSender + Send + Sync>>>,
-	/// [APNSSender] for communication with Apple Push Notification Service
-	/// Usually [crate::apns::APNSSenderImpl]
-	apns_sender: Option<Arc<dyn APNSSender + Send + Sync>>,
-	/// Hedwig [Settings]
-	settings: Arc<Settings>,
+	/// Hedwig [Settings] and the senders built from them, swapped on reload
+	config: LiveConfig,
 	/// Prometheus [Metrics]
 	counters: Arc<Metrics>,
+	/// Set once Hedwig is shutting down
+	shutdown: Shutdown,
+	/// Results of the readiness checks
+	health: Health,
 }
 
 impl AppState {
@@ -164,14 +354,62 @@ impl AppState {
 		counters: Metrics,
 	) -> Self {
 		AppState {
-			fcm_sender: Arc::new(Mutex::new(fcm_sender)),
-			apns_sender: apns_sender.map(Arc::from),
-			settings: Arc::new(settings),
+			config: LiveConfig::new(Snapshot::new(fcm_sender, apns_sender, settings)),
 			counters: Arc::new(counters),
+			shutdown: Shutdown::default(),
+			health: Health::default(),
+		}
+	}
+
+	/// Current settings and senders
+	#[must_use]
+	pub const fn config(&self) -> &LiveConfig {
+		&self.config
+	}
+
+	/// Prometheus metrics
+	#[must_use]
+	pub fn counters(&self) -> &Metrics {
+		&self.counters
+	}
+
+	/// Shutdown flag shared with the server
+	#[must_use]
+	pub const fn shutdown(&self) -> &Shutdown {
+		&self.shutdown
+	}
+
+	/// Results of the readiness checks
+	#[must_use]
+	pub const fn health(&self) -> &Health {
+		&self.health
+	}
+
+	/// Check whether the currently configured push services are usable and
+	/// record the results
+	pub async fn run_checks(&self) {
+		let config = self.config.current();
+		let mut results = vec![("fcm", config.fcm_sender.lock().await.check().await)];
+
+		if let Some(apns_sender) = &config.apns_sender {
+			results.push(("apns", apns_sender.check().await));
 		}
+		self.health.record(results).await;
 	}
 }
 
+/// Readiness endpoint, fails once Hedwig is shutting down or a push service
+/// can't be used
+async fn ready(
+	State(shutdown): State<Shutdown>,
+	State(health): State<Health>,
+) -> (StatusCode, Json<Readiness>) {
+	let readiness = health.readiness(shutdown.is_triggered()).await;
+	let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+	(status, Json(readiness))
+}
+
 /// Create main Hedwig router.
 ///
 /// # Errors
@@ -182,37 +420,47 @@ pub fn create_router(
 	app_state: AppState,
 	registry: Arc<prometheus::Registry>,
 ) -> Result<Router, Report> {
-	let settings = app_state.settings.clone();
+	// The body limit is only read once, changing it requires a restart
+	let settings = app_state.config.current().settings.clone();
 
 	let usized_limit: usize = settings.hedwig.notification_request_body_size_limit.try_into()?;
 	let notification_body_limit = DefaultBodyLimit::max(usized_limit);
 
 	let http_metrics_middleware = HttpMetricsMiddleware::new(app_state.counters.clone());
+	let access_control = AccessControlLayer::new(app_state.config.clone(), &app_state.counters);
 
 	let router = Router::new()
 		.route("/", get(|| async { Redirect::permanent("/version") }))
-		.route("/_matrix/push/v1/notify", post(matrix_push).layer(notification_body_limit))
+		.route(
+			"/_matrix/push/v1/notify",
+			post(matrix_push).layer(notification_body_limit).layer(access_control),
+		)
 		.layer(OtelAxumLayer::default())
 		.layer(http_metrics_middleware)
 		.route("/metrics", get(metrics_handler).with_state(registry))
 		.route("/health", get(|| async { "" }))
+		.route("/ready", get(ready))
 		.route("/version", get(|| async { VERSION }))
+		.fallback(error::unrecognized)
+		.method_not_allowed_fallback(error::method_not_allowed)
 		.with_state(app_state)
 		// Also takes trailing slash to avoid potential incompabilities
 		.layer(NormalizePathLayer::trim_trailing_slash())
-		.layer(CatchPanicLayer::new());
+		.layer(CatchPanicLayer::custom(error::panic_response));
 
 	Ok(router)
 }
 
 /// Sets up and runs the server
-pub async fn run_server<T: APNSSender + Send + Sync + 'static>(
+///
+/// If a `config_path` is given, the configuration is reloaded from it on
+/// SIGHUP or when the file changes.
+pub async fn run_server(
 	settings: Settings,
 	fcm_sender: Box<dyn FcmSender + Send + Sync>,
-	apns_sender: Option<T>,
+	apns_sender: Option<Box<dyn APNSSender + Send + Sync>>,
+	config_path: Option<String>,
 ) -> Result<(), Report> {
-	let apns_sender =
-		apns_sender.map(|sender| -> Box<dyn APNSSender + Send + Sync> { Box::new(sender) });
 	let addr: SocketAddr = (settings.server.bind_address, settings.server.port).into();
 
 	let registry = prometheus::Registry::new();
@@ -230,12 +478,78 @@ pub async fn run_server<T: APNSSender + Send + Sync + 'static>(
 
 	opentelemetry::global::set_meter_provider(provider);
 
+	let tls = settings.server.tls.clone();
+	let admin = settings.server.admin.clone();
+	let grace_period = Duration::from_secs(settings.server.shutdown_grace_period);
+	let shutdown_delay = Duration::from_secs(settings.server.shutdown_delay);
+	let check_interval = Duration::from_secs(settings.server.readiness_check_interval);
 	let app_state = AppState::new(fcm_sender, apns_sender, settings, metrics);
+	let shutdown = app_state.shutdown().clone();
+
+	app_state.run_checks().await;
+	health::spawn_checks(app_state.clone(), check_interval);
+	if let Some(admin) = &admin {
+		admin::spawn_server(admin, app_state.clone()).await?;
+	}
+	if let Some(config_path) = config_path {
+		reload::watch(config_path, app_state.clone());
+	}
+
+	tokio::spawn({
+		let shutdown = shutdown.clone();
+		async move {
+			shutdown::signal().await;
+			info!("Received shutdown signal, draining in-flight requests");
+			shutdown.trigger();
+		}
+	});
 
 	let router = create_router(app_state, Arc::new(registry))?;
+	let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+	if let Some(tls) = tls {
+		let config = RustlsConfig::from_config(Arc::new(
+			tls::server_config(&tls).wrap_err("Failed to load TLS certificates")?,
+		));
+		tls::watch_certificates(&tls, config.clone());
+
+		let handle = axum_server::Handle::new();
+		tokio::spawn({
+			let handle = handle.clone();
+			async move {
+				shutdown.draining(shutdown_delay).await;
+				handle.graceful_shutdown(Some(grace_period));
+			}
+		});
+
+		info!("Serving HTTPS on {}", addr);
+		return axum_server::bind(addr)
+			.acceptor(ClientCertificateAcceptor::new(config))
+			.handle(handle)
+			.serve(make_service)
+			.await
+			.wrap_err("Failed to start api server");
+	}
 
 	let listener =
 		tokio::net::TcpListener::bind(&addr).await.wrap_err("Failed to bind to address")?;
 
-	axum::serve(listener, router).await.wrap_err("Failed to start api server")
+	let server = axum::serve(listener, make_service)
+		.with_graceful_shutdown({
+			let shutdown = shutdown.clone();
+			async move { shutdown.draining(shutdown_delay).await }
+		})
+		.into_future();
+	let grace_period_expired = async {
+		shutdown.draining(shutdown_delay).await;
+		tokio::time::sleep(grace_period).await;
+	};
+
+	tokio::select! {
+		result = server => result.wrap_err("Failed to start api server"),
+		() = grace_period_expired => {
+			warn!("Shutdown grace period expired, aborting remaining requests");
+			Ok(())
+		}
+	}
 }
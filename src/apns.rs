@@ -19,26 +19,90 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fmt::Debug, fs::File, path::PathBuf};
+use std::{
+	fmt::{self, Debug},
+	fs::File,
+	path::{Path, PathBuf},
+};
 
-use a2::{request::payload::Payload, Client, ClientConfig, Endpoint};
+use a2::{
+	request::payload::PayloadLike, Client, ClientConfig, Endpoint, ErrorReason, NotificationOptions,
+};
 use async_trait::async_trait;
+use serde::Serialize;
 
-use crate::error::{ErrCode, HedwigError};
+use crate::{
+	error::{ErrCode, HedwigError},
+	models::ApnsMessage,
+};
+
+/// Notification to send to a device through APNS
+#[derive(Debug, Serialize)]
+pub struct ApnsNotification {
+	/// Token of the device
+	#[serde(skip)]
+	pub device_token: String,
+	/// Headers of the request
+	#[serde(skip)]
+	pub options: NotificationOptions,
+	/// The notification payload
+	#[serde(flatten)]
+	pub message: ApnsMessage,
+}
+
+impl PayloadLike for ApnsNotification {
+	fn get_device_token(&self) -> &str {
+		&self.device_token
+	}
+
+	fn get_options(&self) -> &NotificationOptions {
+		&self.options
+	}
+}
+
+/// Response of APNS to an accepted notification
+#[derive(Debug, Clone, Serialize)]
+pub struct ApnsResponse {
+	/// Id APNS assigned to the notification
+	pub apns_id: Option<String>,
+	/// HTTP status code returned by APNS
+	pub code: u16,
+}
 
 /// Trait for allowing the use of different senders for APNS messages
 /// This is mainly to make testing possible
 #[async_trait]
 pub trait APNSSender: Debug {
 	/// Send off a message to APNS
-	async fn send(&self, payload: Payload) -> Result<(), HedwigError>;
+	async fn send(&self, notification: ApnsNotification) -> Result<ApnsResponse, HedwigError>;
+
+	/// Check whether messages can currently be sent
+	async fn check(&self) -> Result<(), HedwigError> {
+		Ok(())
+	}
 }
 
 /// Default implementation for APNSSender
-#[derive(Debug)]
 pub struct APNSSenderImpl {
 	/// Client for sending the message
 	client: Client,
+	/// Path to the APNS private key (.p8 file)
+	key_file_path: PathBuf,
+	/// Apple developer team id
+	team_id: String,
+	/// Id of the APNS private key
+	key_id: String,
+	/// Whether the sandbox endpoint is used
+	sandbox: bool,
+}
+
+impl Debug for APNSSenderImpl {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("APNSSenderImpl")
+			.field("key_file_path", &self.key_file_path)
+			.field("sandbox", &self.sandbox)
+			.finish_non_exhaustive()
+	}
 }
 
 impl APNSSenderImpl {
@@ -49,6 +113,18 @@ impl APNSSenderImpl {
 		key_id: String,
 		sandbox: bool,
 	) -> Result<Self, HedwigError> {
+		let client = Self::client(&key_file_path, &team_id, &key_id, sandbox)?;
+
+		Ok(Self { client, key_file_path, team_id, key_id, sandbox })
+	}
+
+	/// Load the private key and create a client signing its requests with it
+	fn client(
+		key_file_path: &Path,
+		team_id: &str,
+		key_id: &str,
+		sandbox: bool,
+	) -> Result<Client, HedwigError> {
 		let mut private_key = File::open(key_file_path).map_err(|e| HedwigError {
 			error: e.to_string(),
 			errcode: ErrCode::APNSPrivateKeyNotFound,
@@ -60,29 +136,60 @@ impl APNSSenderImpl {
 		let client_config = ClientConfig::new(endpoint);
 
 		// Connecting to APNs
-		let client = Client::token(&mut private_key, key_id, team_id, client_config)
-			.map_err(|e| HedwigError { error: e.to_string(), errcode: ErrCode::APNSAuthFailed })?;
-
-		Ok(Self { client })
+		Client::token(&mut private_key, key_id.to_owned(), team_id.to_owned(), client_config)
+			.map_err(|e| HedwigError { error: e.to_string(), errcode: ErrCode::APNSAuthFailed })
 	}
 }
 
 #[async_trait]
 impl APNSSender for APNSSenderImpl {
-	async fn send(&self, payload: Payload) -> Result<(), HedwigError> {
-		let response = self
-			.client
-			.send(payload)
-			.await
-			.map_err(|e| HedwigError { errcode: ErrCode::APNSFailed, error: e.to_string() })?;
+	async fn send(&self, notification: ApnsNotification) -> Result<ApnsResponse, HedwigError> {
+		let response = self.client.send(notification).await.map_err(|e| match e {
+			a2::Error::ResponseError(response) => response.error.map_or_else(
+				|| HedwigError {
+					errcode: ErrCode::APNSFailed,
+					error: format!("APNS responded with status {}", response.code),
+				},
+				|error| HedwigError {
+					errcode: errcode(&error.reason),
+					error: format!("Failed sending notification to APNS: {}", error.reason),
+				},
+			),
+			e => HedwigError { errcode: ErrCode::APNSFailed, error: e.to_string() },
+		})?;
 
 		if let Some(error) = response.error {
 			return Err(HedwigError {
-				errcode: ErrCode::APNSFailed,
+				errcode: errcode(&error.reason),
 				error: format!("Failed sending notification to APNS: {}", error.reason),
 			});
 		}
 
-		Ok(())
+		Ok(ApnsResponse { apns_id: response.apns_id, code: response.code })
+	}
+
+	/// Checks whether the private key still loads and can sign a token
+	async fn check(&self) -> Result<(), HedwigError> {
+		Self::client(&self.key_file_path, &self.team_id, &self.key_id, self.sandbox).map(drop)
+	}
+}
+
+/// Map the reason APNS gave for rejecting a notification to an [ErrCode]
+const fn errcode(reason: &ErrorReason) -> ErrCode {
+	match reason {
+		ErrorReason::BadDeviceToken
+		| ErrorReason::DeviceTokenNotForTopic
+		| ErrorReason::Unregistered => ErrCode::Unregistered,
+		ErrorReason::TooManyRequests | ErrorReason::TooManyProviderTokenUpdates => {
+			ErrCode::QuotaExceeded
+		}
+		ErrorReason::ExpiredProviderToken
+		| ErrorReason::InvalidProviderToken
+		| ErrorReason::MissingProviderToken
+		| ErrorReason::Forbidden => ErrCode::APNSAuthFailed,
+		ErrorReason::InternalServerError
+		| ErrorReason::ServiceUnavailable
+		| ErrorReason::Shutdown => ErrCode::ProviderUnavailable,
+		_ => ErrCode::APNSFailed,
 	}
 }
@@ -0,0 +1,146 @@
+//! Command line interface
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color_eyre::{eyre::WrapErr, Report};
+use opentelemetry::global;
+
+use crate::{
+	models::{Data, Device, Metrics, Notification, NotificationMethod},
+	pusher, reload,
+	settings::Settings,
+};
+
+/// Matrix push gateway
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+	/// Path to the config file, `PUSHGW__` environment variables take
+	/// precedence over it
+	#[arg(short, long, global = true, default_value = Settings::CONFIG_FILENAME)]
+	pub config: String,
+	/// What to do, runs the push gateway if omitted
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+/// Subcommands of the CLI
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Run the push gateway
+	Serve,
+	/// Check the config, including loading the FCM credentials and APNs key
+	Validate,
+	/// Print the effective config with secrets redacted
+	PrintConfig,
+	/// Send a test notification to a single device
+	SendTest(SendTest),
+}
+
+/// Arguments of the `send-test` subcommand
+#[derive(Debug, Args)]
+pub struct SendTest {
+	/// App id of the pusher, as the homeserver would send it
+	#[arg(long)]
+	pub app_id: String,
+	/// Pushkey of the device
+	#[arg(long)]
+	pub pushkey: String,
+	/// Service to push through
+	#[arg(long, value_enum, default_value_t)]
+	pub via: Service,
+	/// Data message format to request, e.g. `android`, `ios` or `web`
+	#[arg(long)]
+	pub data_message: Option<String>,
+}
+
+/// Push service selectable on the command line
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Service {
+	/// Firebase Cloud Messaging
+	#[default]
+	Fcm,
+	/// Apple Push Notification Service
+	Apns,
+}
+
+impl From<Service> for NotificationMethod {
+	fn from(service: Service) -> Self {
+		match service {
+			Service::Fcm => Self::Fcm,
+			Service::Apns => Self::Apns,
+		}
+	}
+}
+
+/// Check the config and whether the push services can be authenticated with
+#[allow(clippy::print_stdout)]
+pub async fn validate(config_path: &str) -> Result<(), Report> {
+	let config = reload::load(config_path).await?;
+
+	config.fcm_sender.lock().await.check().await.wrap_err("FCM token could not be minted")?;
+	if let Some(apns_sender) = &config.apns_sender {
+		apns_sender.check().await.wrap_err("APNS key could not be loaded")?;
+	}
+
+	println!("Configuration in {config_path} is valid");
+	Ok(())
+}
+
+/// Print the merged config file and environment with secrets redacted
+#[allow(clippy::print_stdout)]
+pub fn print_config(config_path: &str) -> Result<(), Report> {
+	let config = Settings::load_redacted(config_path).wrap_err("Failed to load configuration")?;
+
+	println!("{}", serde_json::to_string_pretty(&config)?);
+	Ok(())
+}
+
+/// Send a test notification through the same code path as the notify
+/// endpoint, without retries
+#[allow(clippy::print_stdout)]
+pub async fn send_test(config_path: &str, args: SendTest) -> Result<(), Report> {
+	let config = reload::load(config_path).await?;
+
+	let device = Device {
+		app_id: args.app_id,
+		pushkey: args.pushkey,
+		pushkey_ts: None,
+		data: args.data_message.map(|data_message| Data {
+			data_message: Some(data_message),
+			format: None,
+			data: HashMap::new(),
+		}),
+		tweaks: None,
+		notify_via: Some(args.via.into()),
+	};
+	let notification = Notification::test(device.clone());
+
+	// Nothing exports metrics here, the global meter discards them
+	let metrics = Metrics::new(&global::meter("Hedwig"));
+	let response = pusher::push_notification(&notification, &device, &config, &metrics)
+		.await
+		.wrap_err("Sending the test notification failed")?;
+
+	println!("{}", serde_json::to_string_pretty(&response)?);
+	Ok(())
+}
@@ -0,0 +1,241 @@
+//! Encrypting the notification data for devices publishing a public key, so
+//! the push services never see room or sender metadata
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use ctr::{
+	cipher::{KeyIvInit, StreamCipher},
+	Ctr128BE,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tracing::debug;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+	error::{ErrCode, HedwigError},
+	models::{Device, Notification, NotificationData},
+	settings::ProviderPrivacy,
+};
+
+/// Algorithm devices set in their pusher data to get encrypted notifications
+pub const ALGORITHM: &str = "com.famedly.curve25519-aes-sha2";
+
+/// Length of the truncated MAC in bytes
+const MAC_LENGTH: usize = 8;
+
+/// Largest plaintext to encrypt, so the base64 ciphertext still fits into the
+/// 4 KB FCM data and APNs payload limits next to the unencrypted fields
+const MAX_PLAINTEXT_SIZE: usize = 2048;
+
+/// Fields of the plaintext the client can fetch itself, in the order they are
+/// dropped to fit [MAX_PLAINTEXT_SIZE]
+const DROPPABLE_FIELDS: &[&str] = &["content", "room_name", "room_alias", "sender_display_name"];
+
+/// Encrypted payload, with the same fields the homeserver sends for
+/// notifications it encrypted itself
+#[derive(Debug, Clone)]
+pub struct EncryptedPayload {
+	/// The encrypted payload
+	pub ciphertext: String,
+	/// Public key of the ephemeral key pair used for the key agreement
+	pub ephemeral: String,
+	/// Truncated HMAC-SHA-256 of the ciphertext
+	pub mac: String,
+}
+
+/// Encrypts the plaintext for the given unpadded base64 Curve25519 public key
+///
+/// The keys are derived from an ECDH key agreement with an ephemeral key pair
+/// using HKDF-SHA-256 with a zero salt: 32 bytes AES-256 key, 32 bytes MAC key
+/// and 16 bytes AES-CTR IV. All fields are unpadded base64.
+pub fn encrypt(public_key: &str, plaintext: &[u8]) -> Result<EncryptedPayload, HedwigError> {
+	let mut ephemeral_secret = [0_u8; 32];
+	OsRng.fill_bytes(&mut ephemeral_secret);
+
+	encrypt_with_ephemeral_key(ephemeral_secret, public_key, plaintext)
+}
+
+/// [encrypt] with the given ephemeral secret key instead of a random one,
+/// only meant for known answer tests
+pub(crate) fn encrypt_with_ephemeral_key(
+	ephemeral_secret: [u8; 32],
+	public_key: &str,
+	plaintext: &[u8],
+) -> Result<EncryptedPayload, HedwigError> {
+	let public_key: [u8; 32] = STANDARD_NO_PAD
+		.decode(public_key.trim_end_matches('='))
+		.ok()
+		.and_then(|key| key.try_into().ok())
+		.ok_or_else(invalid_key)?;
+
+	let ephemeral_secret = StaticSecret::from(ephemeral_secret);
+	let ephemeral = PublicKey::from(&ephemeral_secret);
+	let shared_secret = ephemeral_secret.diffie_hellman(&PublicKey::from(public_key));
+	// Low order public keys give a shared secret anyone can derive
+	if !shared_secret.was_contributory() {
+		return Err(invalid_key());
+	}
+
+	let mut keys = [0_u8; 80];
+	Hkdf::<Sha256>::new(Some(&[0; 32]), shared_secret.as_bytes())
+		.expand(&[], &mut keys)
+		.map_err(|e| crypto_error(&e))?;
+	let (aes_key, rest) = keys.split_at(32);
+	let (mac_key, iv) = rest.split_at(32);
+
+	let mut ciphertext = plaintext.to_vec();
+	Ctr128BE::<Aes256>::new_from_slices(aes_key, iv)
+		.map_err(|e| crypto_error(&e))?
+		.apply_keystream(&mut ciphertext);
+
+	let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).map_err(|e| crypto_error(&e))?;
+	mac.update(&ciphertext);
+	let mac = mac.finalize().into_bytes();
+
+	Ok(EncryptedPayload {
+		ciphertext: STANDARD_NO_PAD.encode(ciphertext),
+		ephemeral: STANDARD_NO_PAD.encode(ephemeral.as_bytes()),
+		mac: STANDARD_NO_PAD.encode(&mac[..MAC_LENGTH]),
+	})
+}
+
+/// Returns a copy of the notification for the device with its data and deep
+/// link encrypted and the room, event and sender metadata removed, if the
+/// device asks for encrypted notifications
+///
+/// Notifications the homeserver already encrypted are left as they are.
+pub fn encrypt_notification(
+	notification: &Notification,
+	device: &Device,
+	link: Option<String>,
+) -> Result<Option<Notification>, HedwigError> {
+	if !device.wants_encryption() || notification.ciphertext.is_some() {
+		return Ok(None);
+	}
+
+	let public_key = device.public_key().ok_or_else(|| HedwigError {
+		error: format!("Pusher data with {ALGORITHM} is missing the public_key"),
+		errcode: ErrCode::BadJson,
+	})?;
+	let payload = encrypt(public_key, &plaintext(notification, device, link)?)?;
+
+	Ok(Some(Notification {
+		event_id: None,
+		room_id: None,
+		r#type: None,
+		sender: None,
+		sender_display_name: None,
+		room_name: None,
+		room_alias: None,
+		content: None,
+		user_is_target: None,
+		sender_avatar_url: None,
+		room_avatar_url: None,
+		ciphertext: Some(payload.ciphertext),
+		ephemeral: Some(payload.ephemeral),
+		mac: Some(payload.mac),
+		..notification.clone()
+	}))
+}
+
+/// Notification data to encrypt for the device, dropping the fields the client
+/// can fetch itself until it fits [MAX_PLAINTEXT_SIZE]
+fn plaintext(
+	notification: &Notification,
+	device: &Device,
+	link: Option<String>,
+) -> Result<Vec<u8>, HedwigError> {
+	// Only the device can read it, so it gets everything the pusher format
+	// allows except for the device list
+	let data = NotificationData {
+		devices: None,
+		link,
+		..notification.data(device, ProviderPrivacy::Full)?
+	};
+	let mut data = serde_json::to_value(data)?;
+
+	for field in DROPPABLE_FIELDS {
+		let size = serde_json::to_vec(&data)?.len();
+		if size <= MAX_PLAINTEXT_SIZE {
+			break;
+		}
+		if data.as_object_mut().and_then(|data| data.remove(*field)).is_some() {
+			debug!("Dropped {field} from encrypted notification of {size} bytes");
+		}
+	}
+
+	let plaintext = serde_json::to_vec(&data)?;
+	if plaintext.len() > MAX_PLAINTEXT_SIZE {
+		return Err(HedwigError {
+			error: format!("Notification of {} bytes is too large to encrypt", plaintext.len()),
+			errcode: ErrCode::EncryptionFailed,
+		});
+	}
+	Ok(plaintext)
+}
+
+/// Error for public keys that can't be used for encryption
+fn invalid_key() -> HedwigError {
+	HedwigError { error: "Invalid Curve25519 public key".to_owned(), errcode: ErrCode::BadJson }
+}
+
+/// Error for failures of the cryptographic primitives, which only fail for
+/// invalid lengths
+fn crypto_error(error: &impl std::fmt::Display) -> HedwigError {
+	HedwigError {
+		error: format!("Failed encrypting the notification: {error}"),
+		errcode: ErrCode::EncryptionFailed,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	//! Known answer test for the encryption with a fixed ephemeral key
+
+	use super::encrypt_with_ephemeral_key;
+
+	/// Encrypting for the RFC 7748 key pairs gives the same result as an
+	/// independent implementation
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn known_answer() {
+		// Key pairs from RFC 7748, section 6.1
+		let ephemeral_secret: [u8; 32] = [
+			0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2,
+			0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5,
+			0x1d, 0xb9, 0x2c, 0x2a,
+		];
+		let public_key = "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08";
+
+		let payload = encrypt_with_ephemeral_key(
+			ephemeral_secret,
+			public_key,
+			br#"{"room_id":"!owo:example.org"}"#,
+		)
+		.unwrap();
+		assert_eq!(payload.ephemeral, "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo");
+		assert_eq!(payload.ciphertext, "loBIX8CPLF36HdRzwc5fQHOxmHt18hQ3PXTnja4b");
+		assert_eq!(payload.mac, "t/E/VwFu+Kw");
+	}
+}
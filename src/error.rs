@@ -17,19 +17,37 @@
  *   You should have received a copy of the GNU Affero General Public License
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::{
+	any::Any,
+	fmt::{Display, Formatter, Result as FmtResult},
+};
 
 //use actix_web::{http::StatusCode, HttpResponse, ResponseError};
-use axum::{http::StatusCode, response::Response, Json};
+use axum::{
+	extract::rejection::JsonRejection,
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	Json,
+};
 use serde::Serialize;
-use tracing::error;
+use tracing::{debug, error};
 
-/// Matrix error types
-#[derive(Serialize, Debug, PartialEq, Eq)]
+/// Hedwig error types, reported as spec errcodes in responses
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ErrCode {
 	/// The notification json is malformed
 	BadJson,
+	/// The request body isn't valid JSON
+	NotJson,
+	/// The request body is over the size limit
+	TooLarge,
+	/// The endpoint doesn't exist
+	Unrecognized,
+	/// The endpoint doesn't support the request method
+	MethodNotAllowed,
+	/// An unexpected server side failure
+	Unknown,
 	/// Fcm notification building/sending failure
 	FcmFailed,
 	/// Fcm Auth failure
@@ -42,6 +60,119 @@ pub enum ErrCode {
 	APNSFailed,
 	/// APNS not configured
 	APNSNotConfigured,
+	/// The request did not carry valid credentials
+	Unauthorized,
+	/// The request is not allowed to use this endpoint
+	Forbidden,
+	/// The request carried an access token that isn't known
+	UnknownToken,
+	/// The configuration could not be loaded
+	ConfigFailed,
+	/// The push service no longer knows the pushkey
+	Unregistered,
+	/// The push service rate limited us
+	QuotaExceeded,
+	/// The push service is temporarily unavailable
+	ProviderUnavailable,
+	/// Encrypting the notification for the device failed
+	EncryptionFailed,
+}
+
+impl ErrCode {
+	/// HTTP status code to respond with for this error, server side faults
+	/// and push service failures are 5xx
+	#[must_use]
+	pub const fn status_code(&self) -> StatusCode {
+		match self {
+			Self::BadJson | Self::NotJson => StatusCode::BAD_REQUEST,
+			Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+			Self::Unauthorized => StatusCode::UNAUTHORIZED,
+			Self::Forbidden | Self::UnknownToken => StatusCode::FORBIDDEN,
+			Self::Unrecognized | Self::Unregistered => StatusCode::NOT_FOUND,
+			Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+			Self::QuotaExceeded | Self::ProviderUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+			Self::FcmFailed
+			| Self::FcmAuthFailed
+			| Self::APNSPrivateKeyNotFound
+			| Self::APNSAuthFailed
+			| Self::APNSFailed
+			| Self::APNSNotConfigured
+			| Self::ConfigFailed
+			| Self::EncryptionFailed
+			| Self::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+
+	/// Errcode from the Matrix spec to respond with for this error
+	#[must_use]
+	pub const fn matrix_errcode(&self) -> &'static str {
+		match self {
+			Self::BadJson => "M_BAD_JSON",
+			Self::NotJson => "M_NOT_JSON",
+			Self::TooLarge => "M_TOO_LARGE",
+			Self::Unauthorized => "M_MISSING_TOKEN",
+			Self::Forbidden => "M_FORBIDDEN",
+			Self::UnknownToken => "M_UNKNOWN_TOKEN",
+			Self::Unrecognized | Self::MethodNotAllowed => "M_UNRECOGNIZED",
+			Self::Unregistered => "M_NOT_FOUND",
+			Self::FcmFailed
+			| Self::FcmAuthFailed
+			| Self::APNSPrivateKeyNotFound
+			| Self::APNSAuthFailed
+			| Self::APNSFailed
+			| Self::APNSNotConfigured
+			| Self::ConfigFailed
+			| Self::QuotaExceeded
+			| Self::ProviderUnavailable
+			| Self::EncryptionFailed
+			| Self::Unknown => "M_UNKNOWN",
+		}
+	}
+
+	/// Whether a push failing with this error means the pushkey is invalid,
+	/// so the homeserver should remove the pusher
+	///
+	/// Server side and push service faults never reject a pushkey, so errors
+	/// with these codes must only come from the request itself.
+	#[must_use]
+	pub const fn rejects_pushkey(&self) -> bool {
+		matches!(
+			self,
+			Self::Unregistered
+				| Self::BadJson
+				| Self::NotJson
+				| Self::TooLarge
+				| Self::Unrecognized
+				| Self::MethodNotAllowed
+		)
+	}
+
+	/// Normalised reason for a failed push, used as metrics label
+	#[must_use]
+	pub const fn reason(&self) -> &'static str {
+		match self {
+			Self::Unregistered => "Unregistered",
+			Self::QuotaExceeded => "QuotaExceeded",
+			Self::FcmAuthFailed | Self::APNSAuthFailed | Self::APNSPrivateKeyNotFound => {
+				"AuthError"
+			}
+			Self::ProviderUnavailable => "Unavailable",
+			Self::BadJson
+			| Self::NotJson
+			| Self::TooLarge
+			| Self::Unrecognized
+			| Self::MethodNotAllowed => "InvalidRequest",
+			Self::APNSNotConfigured => "NotConfigured",
+			Self::FcmFailed
+			| Self::APNSFailed
+			| Self::Unauthorized
+			| Self::Forbidden
+			| Self::UnknownToken
+			| Self::ConfigFailed
+			| Self::EncryptionFailed
+			| Self::Unknown => "Other",
+		}
+	}
 }
 
 /// Matrix error
@@ -64,10 +195,25 @@ impl Display for HedwigError {
 impl From<firebae_cm::Error> for HedwigError {
 	fn from(err: firebae_cm::Error) -> Self {
 		error!("fcm error: {}", err);
-		Self {
-			error: "Something went wrong while trying to interact with fcm".to_owned(),
-			errcode: ErrCode::FcmFailed,
-		}
+		let errcode = match &err {
+			firebae_cm::Error::FcmError(e) => match e.status.as_str() {
+				"NOT_FOUND" | "UNREGISTERED" => ErrCode::Unregistered,
+				"RESOURCE_EXHAUSTED" | "QUOTA_EXCEEDED" => ErrCode::QuotaExceeded,
+				"UNAUTHENTICATED" | "PERMISSION_DENIED" | "THIRD_PARTY_AUTH_ERROR" => {
+					ErrCode::FcmAuthFailed
+				}
+				"UNAVAILABLE" | "INTERNAL" => ErrCode::ProviderUnavailable,
+				_ => ErrCode::FcmFailed,
+			},
+			_ => ErrCode::FcmFailed,
+		};
+		let error = match &err {
+			firebae_cm::Error::FcmError(e) => {
+				format!("FCM responded with {} ({}): {}", e.status, e.code, e.message)
+			}
+			_ => format!("Something went wrong while trying to interact with fcm: {err}"),
+		};
+		Self { error, errcode }
 	}
 }
 
@@ -81,17 +227,75 @@ impl From<gcp_auth::Error> for HedwigError {
 	}
 }
 
+/// Only used for building payloads, malformed requests are turned into
+/// errors through [JsonRejection]
 impl From<serde_json::Error> for HedwigError {
 	fn from(err: serde_json::Error) -> Self {
-		Self { error: err.to_string(), errcode: ErrCode::BadJson }
+		Self {
+			error: format!("Failed to serialize the notification: {err}"),
+			errcode: ErrCode::Unknown,
+		}
 	}
 }
 
-impl axum::response::IntoResponse for HedwigError {
+/// Error response body as defined by the Matrix spec
+#[derive(Serialize, Debug)]
+struct MatrixError {
+	/// Errcode from the spec
+	errcode: &'static str,
+	/// The error text
+	error: String,
+	/// The more specific Hedwig error type
+	#[serde(rename = "com.famedly.hedwig.errcode")]
+	hedwig_errcode: ErrCode,
+}
+
+impl IntoResponse for HedwigError {
 	fn into_response(self) -> Response {
-		error!("Failed extracting request body {}", &self.error);
-		let mut res = Json(self).into_response();
-		*res.status_mut() = StatusCode::BAD_REQUEST;
-		res
+		let status = self.errcode.status_code();
+		if status.is_server_error() {
+			error!(errcode = ?self.errcode, "Request failed: {}", self.error);
+		} else {
+			debug!(errcode = ?self.errcode, "Rejected request: {}", self.error);
+		}
+
+		let body = MatrixError {
+			errcode: self.errcode.matrix_errcode(),
+			error: self.error,
+			hedwig_errcode: self.errcode,
+		};
+		(status, Json(body)).into_response()
 	}
 }
+
+impl From<JsonRejection> for HedwigError {
+	fn from(rejection: JsonRejection) -> Self {
+		let errcode = match &rejection {
+			JsonRejection::JsonDataError(_) => ErrCode::BadJson,
+			JsonRejection::JsonSyntaxError(_) | JsonRejection::MissingJsonContentType(_) => {
+				ErrCode::NotJson
+			}
+			_ if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => ErrCode::TooLarge,
+			_ => ErrCode::BadJson,
+		};
+		Self { error: rejection.to_string(), errcode }
+	}
+}
+
+/// Response for requests to endpoints that don't exist
+#[allow(clippy::unused_async)]
+pub async fn unrecognized() -> HedwigError {
+	HedwigError { error: "Unrecognized request".to_owned(), errcode: ErrCode::Unrecognized }
+}
+
+/// Response for requests using a method the endpoint doesn't support
+#[allow(clippy::unused_async)]
+pub async fn method_not_allowed() -> HedwigError {
+	HedwigError { error: "Method not allowed".to_owned(), errcode: ErrCode::MethodNotAllowed }
+}
+
+/// Response for requests whose handler panicked
+pub fn panic_response(_panic: Box<dyn Any + Send + 'static>) -> Response {
+	HedwigError { error: "Internal server error".to_owned(), errcode: ErrCode::Unknown }
+		.into_response()
+}
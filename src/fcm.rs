@@ -29,15 +29,24 @@ use std::{
 use async_trait::async_trait;
 use firebae_cm::{Message, MessageBody};
 use gcp_auth::TokenProvider;
+use tracing::{info_span, Instrument};
 
 use crate::error::HedwigError;
 
+/// OAuth scope needed for sending messages through fcm
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
 /// Trait for allowing the use of different senders for fcm messages
 /// This is mainly to make testing possible
 #[async_trait]
 pub trait FcmSender: Debug {
 	/// Send off a message to fcm
 	async fn send(&self, message: MessageBody) -> Result<String, HedwigError>;
+
+	/// Check whether messages can currently be sent
+	async fn check(&self) -> Result<(), HedwigError> {
+		Ok(())
+	}
 }
 
 /// Default implementation for FcmSender
@@ -72,11 +81,19 @@ impl FcmSender for FcmSenderImpl {
 		let client = firebae_cm::Client::new();
 		let token = self
 			.provider
-			.token(&["https://www.googleapis.com/auth/firebase.messaging"])
+			.token(&[FCM_SCOPE])
+			.instrument(info_span!("fcm.token"))
 			.await
 			.map(|e| e.as_str().to_owned());
 		let message = Message::new(self.project_id.clone(), token?, body);
 
 		Ok(client.send(message).await?)
 	}
+
+	/// Checks whether an access token can be minted, the provider caches it
+	/// until it expires
+	async fn check(&self) -> Result<(), HedwigError> {
+		self.provider.token(&[FCM_SCOPE]).await?;
+		Ok(())
+	}
 }
@@ -0,0 +1,117 @@
+//! Readiness checks for the services Hedwig pushes through
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{sync::RwLock, task::JoinHandle, time::MissedTickBehavior};
+use tracing::warn;
+
+use crate::{api::AppState, error::HedwigError};
+
+/// Result of the last check of a component
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentStatus {
+	/// Whether the last check succeeded
+	pub healthy: bool,
+	/// When the component was last checked
+	#[serde(with = "time::serde::rfc3339")]
+	pub last_checked: OffsetDateTime,
+	/// Why the last check failed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+/// Response body of the readiness endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+	/// Whether Hedwig is able to deliver pushes
+	pub ready: bool,
+	/// Whether a shutdown is in progress
+	pub shutting_down: bool,
+	/// Status of the individual components, keyed by name
+	pub components: BTreeMap<&'static str, ComponentStatus>,
+}
+
+/// Cached results of the component checks
+///
+/// Checks are run in the background, so the readiness endpoint never waits
+/// for Google or Apple.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+	/// Last status of every configured component
+	components: Arc<RwLock<BTreeMap<&'static str, ComponentStatus>>>,
+}
+
+impl Health {
+	/// Record the results of checking every configured component
+	///
+	/// Components missing from the results are no longer configured, so their
+	/// last status is dropped.
+	pub async fn record(&self, results: Vec<(&'static str, Result<(), HedwigError>)>) {
+		let last_checked = OffsetDateTime::now_utc();
+		let components = results
+			.into_iter()
+			.map(|(component, result)| {
+				if let Err(e) = &result {
+					warn!("Readiness check of {} failed: {}", component, e);
+				}
+
+				let status = ComponentStatus {
+					healthy: result.is_ok(),
+					last_checked,
+					error: result.err().map(|e| e.error),
+				};
+				(component, status)
+			})
+			.collect();
+
+		*self.components.write().await = components;
+	}
+
+	/// Last status of every configured component
+	pub async fn components(&self) -> BTreeMap<&'static str, ComponentStatus> {
+		self.components.read().await.clone()
+	}
+
+	/// Current readiness, based on the last check of every component
+	pub async fn readiness(&self, shutting_down: bool) -> Readiness {
+		let components = self.components().await;
+		let ready = !shutting_down && components.values().all(|status| status.healthy);
+
+		Readiness { ready, shutting_down, components }
+	}
+}
+
+/// Spawns a task re-running the readiness checks every `interval`
+pub fn spawn_checks(app_state: AppState, interval: Duration) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(interval);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		// The first tick completes immediately
+		ticker.tick().await;
+
+		loop {
+			ticker.tick().await;
+			app_state.run_checks().await;
+		}
+	})
+}
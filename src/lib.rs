@@ -18,11 +18,20 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod access;
+pub mod admin;
 pub mod api;
 pub mod apns;
+pub mod cli;
+pub mod encryption;
 pub mod error;
 pub mod fcm;
+pub mod health;
 pub mod metrics;
 pub mod models;
 pub mod pusher;
+pub mod reload;
 pub mod settings;
+pub mod shutdown;
+pub mod tls;
+pub mod watcher;
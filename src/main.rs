@@ -19,51 +19,60 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod access;
+mod admin;
 mod api;
 mod apns;
+mod cli;
+mod encryption;
 mod error;
 mod fcm;
+mod health;
 mod metrics;
 mod models;
 mod pusher;
+mod reload;
 mod settings;
+mod shutdown;
+mod tls;
+mod watcher;
 
-use color_eyre::{eyre::WrapErr, Report};
+use clap::Parser;
+use color_eyre::Report;
 use tracing::info;
 
-use crate::{apns::APNSSenderImpl, fcm::FcmSenderImpl};
+use crate::cli::{Cli, Command};
 
 #[tokio::main]
+async fn main() -> Result<(), Report> {
+	let cli = Cli::parse();
+
+	match cli.command.unwrap_or(Command::Serve) {
+		Command::Serve => serve(cli.config).await,
+		Command::Validate => cli::validate(&cli.config).await,
+		Command::PrintConfig => cli::print_config(&cli.config),
+		Command::SendTest(args) => cli::send_test(&cli.config, args).await,
+	}
+}
+
+/// Run the push gateway
 // Need to be able to print errors before the logger is up
 #[allow(clippy::print_stderr)]
-async fn main() -> Result<(), Report> {
+async fn serve(config_path: String) -> Result<(), Report> {
 	// Complete failure if config file is missing
-	let settings = settings::Settings::load(settings::Settings::CONFIG_FILENAME)?;
+	let settings = settings::Settings::load(&config_path)?;
+	settings.validate()?;
 
 	rust_telemetry::init_otel(&settings.telemetry, "Hedwig", "2.0.0", "Hedwig")?;
 
-	info!("Launching with settings: {:?}", settings);
-
-	let fcm_auth = FcmSenderImpl::new(&settings.hedwig.fcm_credentials_file_path)
-		.await
-		.wrap_err("Fcm setup failed")?;
+	info!(
+		"Launching Hedwig for {} on {}:{}",
+		settings.hedwig.app_id, settings.server.bind_address, settings.server.port
+	);
 
-	let apns_auth = settings
-		.hedwig
-		.apns_key_file_path
-		.as_ref()
-		.map(|path| {
-			APNSSenderImpl::new(
-				path.clone(),
-				settings.hedwig.apns_team_id.clone(),
-				settings.hedwig.apns_key_id.clone(),
-				settings.hedwig.apns_sandbox,
-			)
-			.wrap_err("APNS authentication failed")
-		})
-		.transpose()?;
+	let (fcm_sender, apns_sender) = reload::create_senders(&settings).await?;
 	info!("Starting server");
-	api::run_server(settings, Box::new(fcm_auth), apns_auth).await?;
+	api::run_server(settings, fcm_sender, apns_sender, Some(config_path)).await?;
 
 	Ok(())
 }
@@ -18,16 +18,26 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashMap;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::{self, Write},
+	time::Duration,
+};
 
 use axum::{body::Body, extract::FromRequest, http::Request, Json};
 use firebae_cm::{FirebaseMap, IntoFirebaseMap};
-use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::{
+	metrics::{Counter, Histogram, Meter},
+	KeyValue,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
+	apns::ApnsResponse,
+	encryption,
 	error::{ErrCode, HedwigError},
-	settings::DeserializablePushType,
+	settings::{DeserializablePushType, ProviderPrivacy},
 };
 
 /// The notification priority
@@ -54,13 +64,17 @@ pub struct Counts {
 pub struct Data {
 	/// The data message format
 	pub data_message: Option<String>,
+	/// Format of the notifications, `event_id_only` if the device should only
+	/// receive the event and room id
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub format: Option<String>,
 	/// The rest of the device data
 	#[serde(flatten)]
 	pub data: HashMap<String, serde_json::Value>,
 }
 
 /// The device notification is sent for
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Device {
 	/// ID of the application
 	pub app_id: String,
@@ -77,6 +91,28 @@ pub struct Device {
 	pub notify_via: Option<NotificationMethod>,
 }
 
+impl fmt::Debug for Device {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Device")
+			.field("app_id", &self.app_id)
+			.field("pushkey", &hash_pushkey(&self.pushkey))
+			.field("pushkey_ts", &self.pushkey_ts)
+			.field("data", &self.data)
+			.field("tweaks", &self.tweaks)
+			.field("notify_via", &self.notify_via)
+			.finish()
+	}
+}
+
+/// Shortened SHA-256 of a pushkey, to correlate logs without revealing it
+#[must_use]
+pub fn hash_pushkey(pushkey: &str) -> String {
+	Sha256::digest(pushkey.as_bytes()).iter().take(8).fold(String::new(), |mut hex, byte| {
+		let _ = write!(hex, "{byte:02x}");
+		hex
+	})
+}
+
 /// What service to use for sending notifications, fallback to fcm
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 #[serde(rename_all = "lowercase")]
@@ -88,6 +124,89 @@ pub enum NotificationMethod {
 	Apns,
 }
 
+impl NotificationMethod {
+	/// Name of the transport as used in metrics labels
+	#[must_use]
+	pub const fn as_str(&self) -> &'static str {
+		match self {
+			Self::Fcm => "fcm",
+			Self::Apns => "apns",
+		}
+	}
+}
+
+/// What a push service answered to an accepted notification
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ProviderResponse {
+	/// Firebase Cloud Messaging
+	Fcm {
+		/// Name FCM assigned to the message
+		name: String,
+	},
+	/// Apple Push Notification Service
+	Apns(ApnsResponse),
+}
+
+impl ProviderResponse {
+	/// Id the push service assigned to the message, if it returned one
+	#[must_use]
+	pub fn message_id(&self) -> Option<String> {
+		match self {
+			Self::Fcm { name } => Some(name.clone()),
+			Self::Apns(response) => response.apns_id.clone(),
+		}
+	}
+}
+
+/// Result of delivering a notification to a single device
+#[derive(Clone, Serialize)]
+pub struct DeliveryOutcome {
+	/// Pushkey of the device
+	pub pushkey: String,
+	/// Service the notification was pushed through
+	pub transport: NotificationMethod,
+	/// Number of attempts, including the successful one
+	pub attempts: u32,
+	/// Milliseconds from the first attempt until the push was delivered or
+	/// given up on, including retry delays
+	pub latency_ms: u64,
+	/// Whether the push was delivered, and the details
+	#[serde(flatten)]
+	pub status: DeliveryStatus,
+}
+
+impl fmt::Debug for DeliveryOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("DeliveryOutcome")
+			.field("pushkey", &hash_pushkey(&self.pushkey))
+			.field("transport", &self.transport)
+			.field("attempts", &self.attempts)
+			.field("latency_ms", &self.latency_ms)
+			.field("status", &self.status)
+			.finish()
+	}
+}
+
+/// Final status of a push to a single device
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum DeliveryStatus {
+	/// The push service accepted the notification
+	Delivered {
+		/// Id the push service assigned to the message
+		#[serde(skip_serializing_if = "Option::is_none")]
+		message_id: Option<String>,
+	},
+	/// The push failed, even after retrying
+	Failed {
+		/// What went wrong
+		errcode: ErrCode,
+		/// Description of the error
+		error: String,
+	},
+}
+
 /// What kind of data message should be sent (if any)
 #[derive(Debug)]
 pub enum DataMessageType {
@@ -97,6 +216,8 @@ pub enum DataMessageType {
 	Android,
 	/// Apns data message
 	Ios, // Apple would hate me for this capitalization
+	/// Data message for web clients registered through Firebase JS
+	Web,
 }
 
 impl Device {
@@ -104,17 +225,63 @@ impl Device {
 	#[must_use]
 	pub fn data_message_type(&self) -> DataMessageType {
 		// Deprecated, use the data field!
-		// TODO: remove once clients have switched to the new format
-		if self.app_id.ends_with(".data_message") {
+		// TODO: remove once `devices_legacy_total` stays at zero
+		if self.is_legacy_data_message() {
 			return DataMessageType::Android;
 		}
 
 		match self.data.as_ref().and_then(|d| d.data_message.as_ref()) {
 			Some(msg) if msg == "android" => DataMessageType::Android,
 			Some(msg) if msg == "ios" => DataMessageType::Ios,
+			Some(msg) if msg == "web" => DataMessageType::Web,
 			_ => DataMessageType::None,
 		}
 	}
+
+	/// Whether the pusher uses the `event_id_only` format, so the notification
+	/// must not carry the event content or metadata
+	#[must_use]
+	pub fn event_id_only(&self) -> bool {
+		self.data.as_ref().and_then(|d| d.format.as_deref()) == Some("event_id_only")
+	}
+
+	/// Whether the push rules marked the notification as highlight for this
+	/// device, like for mentions
+	#[must_use]
+	pub fn highlight(&self) -> bool {
+		self.tweaks
+			.as_ref()
+			.and_then(|t| t.get("highlight"))
+			.is_some_and(|h| h.as_bool() != Some(false))
+	}
+
+	/// Android notification fields the device wants to override, from the
+	/// `android_notification` object of its pusher data
+	#[must_use]
+	pub fn android_overrides(&self) -> Option<&serde_json::Map<String, serde_json::Value>> {
+		self.data.as_ref()?.data.get("android_notification")?.as_object()
+	}
+
+	/// Whether the device asks for encrypted notifications by setting the
+	/// algorithm in its pusher data
+	#[must_use]
+	pub fn wants_encryption(&self) -> bool {
+		self.data.as_ref().and_then(|d| d.data.get("algorithm")).and_then(serde_json::Value::as_str)
+			== Some(encryption::ALGORITHM)
+	}
+
+	/// Curve25519 public key the device wants its notifications encrypted for
+	#[must_use]
+	pub fn public_key(&self) -> Option<&str> {
+		self.data.as_ref()?.data.get("public_key")?.as_str()
+	}
+
+	/// Whether the device asks for android data messages through the
+	/// deprecated `.data_message` app_id suffix
+	#[must_use]
+	pub fn is_legacy_data_message(&self) -> bool {
+		self.app_id.ends_with(".data_message")
+	}
 }
 
 /// The notification request body
@@ -125,7 +292,7 @@ pub struct NotificationRequest {
 }
 
 /// The notification data
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Notification {
 	/// The Matrix event ID
 	pub event_id: Option<String>,
@@ -160,28 +327,167 @@ pub struct Notification {
 	/// member event (i.e. the state_key of the member event is equal to the
 	/// user’s Matrix ID).
 	pub user_is_target: Option<bool>,
+	/// The `mxc://` avatar of the sender, not part of the spec but sent by
+	/// homeservers knowing it
+	pub sender_avatar_url: Option<String>,
+	/// The `mxc://` avatar of the room, not part of the spec but sent by
+	/// homeservers knowing it
+	pub room_avatar_url: Option<String>,
+}
+
+impl fmt::Debug for Notification {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		/// Stand-in for fields that must not be logged
+		const REDACTED: Option<&str> = Some("<redacted>");
+
+		f.debug_struct("Notification")
+			.field("event_id", &self.event_id)
+			.field("room_id", &self.room_id)
+			.field("type", &self.r#type)
+			.field("sender", &self.sender)
+			.field("sender_display_name", &self.sender_display_name)
+			.field("room_name", &self.room_name)
+			.field("room_alias", &self.room_alias)
+			.field("prio", &self.prio)
+			.field("counts", &self.counts)
+			.field("content", &self.content.as_ref().and(REDACTED))
+			.field("devices", &self.devices)
+			.field("ciphertext", &self.ciphertext.as_ref().and(REDACTED))
+			.field("ephemeral", &self.ephemeral.as_ref().and(REDACTED))
+			.field("mac", &self.mac.as_ref().and(REDACTED))
+			.field("user_is_target", &self.user_is_target)
+			.field("sender_avatar_url", &self.sender_avatar_url)
+			.field("room_avatar_url", &self.room_avatar_url)
+			.finish()
+	}
 }
 
 impl Notification {
+	/// A notification for testing whether pushes reach the given device
+	#[must_use]
+	pub fn test(device: Device) -> Self {
+		Self {
+			event_id: Some("$hedwig-test".to_owned()),
+			room_id: Some("!hedwig-test:localhost".to_owned()),
+			r#type: Some("m.room.message".to_owned()),
+			sender: Some("@hedwig:localhost".to_owned()),
+			sender_display_name: None,
+			room_name: None,
+			room_alias: None,
+			prio: None,
+			counts: Some(Counts { unread: Some(1), missed_calls: None }),
+			content: None,
+			devices: vec![device],
+			ciphertext: None,
+			ephemeral: None,
+			mac: None,
+			user_is_target: None,
+			sender_avatar_url: None,
+			room_avatar_url: None,
+		}
+	}
+
 	/// Returns the data to be attached to the notification
-	pub fn data(&self, device: &Device) -> Result<NotificationData, HedwigError> {
+	///
+	/// Devices using the `event_id_only` format only get the event and room
+	/// id, counts and priority, without any content or device list. The
+	/// privacy level limits the data further.
+	pub fn data(
+		&self,
+		device: &Device,
+		privacy: ProviderPrivacy,
+	) -> Result<NotificationData, HedwigError> {
+		let standard = privacy >= ProviderPrivacy::Standard;
+		let full_format = !device.event_id_only();
+		let full = full_format && privacy == ProviderPrivacy::Full;
+
+		// Pretending there is only one device to avoid going over any size limits
+		let devices = match privacy {
+			ProviderPrivacy::Standard if full_format => {
+				Some(serde_json::to_string(&[serde_json::json!({
+					"app_id": device.app_id,
+					"pushkey": device.pushkey,
+					"data": device.data,
+				})])?)
+			}
+			ProviderPrivacy::Full if full_format => Some(serde_json::to_string(&[device])?),
+			_ => None,
+		};
+
 		Ok(NotificationData {
-			content: serde_json::to_string(&self.content)?,
+			content: self
+				.content
+				.as_ref()
+				.filter(|_| full)
+				.map(serde_json::to_string)
+				.transpose()?,
 			counts: serde_json::to_string(&self.counts)?,
-			// Pretending there is only one device to avoid going over any size limits
-			devices: serde_json::to_string(&[device])?,
+			devices,
 			event_id: self.event_id.clone(),
-			prio: serde_json::to_string(&self.prio)?,
-			room_alias: self.room_alias.clone(),
+			prio: standard.then(|| serde_json::to_string(&self.prio)).transpose()?,
+			room_alias: self.room_alias.clone().filter(|_| full),
 			room_id: self.room_id.clone(),
-			room_name: self.room_name.clone(),
-			sender: self.sender.clone(),
-			sender_display_name: self.sender_display_name.clone(),
-			r#type: self.r#type.clone(),
+			room_name: self.room_name.clone().filter(|_| full),
+			sender: self.sender.clone().filter(|_| full),
+			sender_display_name: self.sender_display_name.clone().filter(|_| full),
+			r#type: self.r#type.clone().filter(|_| full_format && standard),
 			ciphertext: self.ciphertext.clone(),
 			ephemeral: self.ephemeral.clone(),
 			mac: self.mac.clone(),
-			user_is_target: self.user_is_target.map(|x| x.to_string()),
+			link: None,
+			user_is_target: self
+				.user_is_target
+				.filter(|_| full_format && standard)
+				.map(|x| x.to_string()),
+		})
+	}
+
+	/// The `mxc://` URI of the image to attach: the thumbnail or the image of
+	/// an image message, else the avatar of the sender or of the room
+	#[must_use]
+	pub fn image_uri(&self) -> Option<&str> {
+		let image = self
+			.content
+			.as_ref()
+			.filter(|c| c.get("msgtype").and_then(serde_json::Value::as_str) == Some("m.image"));
+
+		image
+			.and_then(|c| c.pointer("/info/thumbnail_url").or_else(|| c.get("url")))
+			.and_then(serde_json::Value::as_str)
+			.or(self.sender_avatar_url.as_deref())
+			.or(self.room_avatar_url.as_deref())
+	}
+
+	/// Whether the notification only updates the unread count, either because
+	/// it isn't about an event in a room (encrypted notifications are) or
+	/// because everything was read
+	#[must_use]
+	pub fn is_badge_only(&self) -> bool {
+		(self.room_id.is_none() && self.ciphertext.is_none())
+			|| self.counts.as_ref().and_then(|c| c.unread) == Some(0)
+	}
+
+	/// Title and body of the alert shown to the user, built from the sender
+	/// and the message body of the event
+	///
+	/// Only available for devices using the full format, the configured
+	/// notification title and body are used otherwise.
+	#[must_use]
+	pub fn alert(&self, device: &Device) -> Option<Alert> {
+		if device.event_id_only() {
+			return None;
+		}
+
+		let sender = self.sender_display_name.as_ref().or(self.sender.as_ref())?;
+		let body = self.content.as_ref()?.get("body")?.as_str()?;
+
+		Some(Alert {
+			title: self.room_name.clone().unwrap_or_else(|| sender.clone()),
+			body: if self.room_name.is_some() {
+				format!("{sender}: {body}")
+			} else {
+				body.to_owned()
+			},
 		})
 	}
 }
@@ -195,12 +501,46 @@ where
 	async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
 		let Json(notification_request) = Json::<NotificationRequest>::from_request(req, state)
 			.await
-			.map_err(|err| HedwigError { error: err.to_string(), errcode: ErrCode::BadJson })?;
+			.map_err(HedwigError::from)?;
 
 		Ok(notification_request.notification)
 	}
 }
 
+/// Alert text of a notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+	/// Title of the alert
+	pub title: String,
+	/// Body of the alert
+	pub body: String,
+}
+
+/// A notification rendered for a device, before it's turned into the message
+/// of a specific push service
+#[derive(Debug)]
+pub struct RenderedNotification {
+	/// Alert to show, none for silent notifications
+	pub alert: Option<Alert>,
+	/// Badge to set on the app icon
+	pub badge: u32,
+	/// Sound to play, none for silent notifications
+	pub sound: Option<String>,
+	/// Data passed on to the app, if it asked for it
+	pub data: Option<NotificationData>,
+	/// Deep link opening the room of the notification
+	pub link: Option<String>,
+	/// URL of an image to attach
+	pub image: Option<String>,
+	/// Delivery priority
+	pub priority: Priority,
+	/// Identifier for notifications replacing each other
+	pub collapse_id: Option<String>,
+	/// Seconds after which an undelivered notification is dropped, sent as
+	/// `ttl` to Android and as the matching UNIX timestamp to APNs
+	pub expiration: Option<u64>,
+}
+
 /// The notification data to be pushed to the client
 #[derive(Deserialize, Serialize, Debug)]
 pub struct NotificationData {
@@ -226,14 +566,17 @@ pub struct NotificationData {
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub room_alias: Option<String>,
 	/// The priority of the notification. If omitted, high is assumed.
-	pub prio: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub prio: Option<String>,
 	/// This is a dictionary of the current number of unacknowledged
 	/// communications for the recipient user.
 	pub counts: String,
 	/// The content field from the event, if present.
-	pub content: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub content: Option<String>,
 	/// This is an array of devices that the notification should be sent to.
-	pub devices: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub devices: Option<String>,
 	/// The ciphertext of an encrypted push payload
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub ciphertext: Option<String>,
@@ -243,6 +586,9 @@ pub struct NotificationData {
 	/// The mac of an encrypted push payload
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub mac: Option<String>,
+	/// Deep link opening the room of the notification
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub link: Option<String>,
 	/// This is true if the user receiving the notification is the subject of a
 	/// member event (i.e. the state_key of the member event is equal to the
 	/// user’s Matrix ID).
@@ -269,12 +615,13 @@ impl IntoFirebaseMap for NotificationData {
 		insert_opt("ciphertext", &self.ciphertext);
 		insert_opt("ephemeral", &self.ephemeral);
 		insert_opt("mac", &self.mac);
+		insert_opt("link", &self.link);
 		insert_opt("user_is_target", &self.user_is_target);
+		insert_opt("prio", &self.prio);
+		insert_opt("content", &self.content);
+		insert_opt("devices", &self.devices);
 
-		map.insert("prio", &self.prio);
 		map.insert("counts", &self.counts);
-		map.insert("content", &self.content);
-		map.insert("devices", &self.devices);
 		map
 	}
 }
@@ -288,15 +635,19 @@ pub struct ApnsHeaders {
 	pub apns_priority: Option<String>,
 	/// Push type
 	pub apns_push_type: DeserializablePushType,
-	/// Expiration in seconds
+	/// Seconds after which an undelivered notification is dropped, also used
+	/// as the Android `ttl`
 	pub apns_expiration: Option<u64>,
 	/// Topic
 	pub apns_topic: Option<String>,
-	/// Collapse ID
+	/// Collapse ID, also used as the Android `collapse_key`
 	pub apns_collapse_id: Option<String>,
 }
 
 /// APNS payload
+///
+/// The text fields support the same placeholders as the notification title
+/// and `<room_id>`.
 #[derive(Debug, Deserialize, Clone)]
 pub struct ApnsPayload {
 	/// Category
@@ -305,6 +656,108 @@ pub struct ApnsPayload {
 	pub mutable_content: Option<u8>,
 	/// content_available
 	pub content_available: Option<u8>,
+	/// Interruption level of notifications
+	#[serde(default)]
+	pub interruption_level: Option<InterruptionLevel>,
+	/// Interruption level of notifications the push rules marked as
+	/// highlight, like mentions
+	#[serde(default)]
+	pub highlight_interruption_level: Option<InterruptionLevel>,
+	/// Relevance score between 0 and 1, used to sort the notification summary
+	#[serde(default)]
+	pub relevance_score: Option<f64>,
+	/// Thread id grouping notifications
+	#[serde(default)]
+	pub thread_id: Option<String>,
+	/// Subtitle of the alert
+	#[serde(default)]
+	pub subtitle: Option<String>,
+	/// Identifier of the app window to bring forward
+	#[serde(default)]
+	pub target_content_id: Option<String>,
+	/// Focus filter criteria
+	#[serde(default)]
+	pub filter_criteria: Option<String>,
+	/// Custom keys added next to `aps`, which itself can't be overridden
+	#[serde(default)]
+	pub custom: BTreeMap<String, serde_json::Value>,
+}
+
+/// How urgently iOS presents a notification
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterruptionLevel {
+	/// Added to the notification list without lighting up the screen or
+	/// playing a sound
+	Passive,
+	/// Presented immediately
+	Active,
+	/// Presented immediately, even when a focus is active
+	TimeSensitive,
+	/// Presented immediately with sound, even when muted
+	Critical,
+}
+
+/// Notification payload for APNS, sent the same way directly and relayed
+/// through FCM
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApnsMessage {
+	/// The `aps` dictionary interpreted by iOS
+	pub aps: Aps,
+	/// Custom keys for the app
+	#[serde(flatten)]
+	pub custom: BTreeMap<String, serde_json::Value>,
+}
+
+/// The `aps` dictionary of an APNS notification
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Aps {
+	/// Alert shown to the user, silent notification if none
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub alert: Option<ApsAlert>,
+	/// Badge on the app icon
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub badge: Option<u32>,
+	/// Sound to play
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sound: Option<String>,
+	/// Thread id grouping notifications
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub thread_id: Option<String>,
+	/// Category for notification actions
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub category: Option<String>,
+	/// Wakes the app in the background if 1
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub content_available: Option<u8>,
+	/// Lets the notification service extension modify the notification if 1
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub mutable_content: Option<u8>,
+	/// Identifier of the app window to bring forward
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub target_content_id: Option<String>,
+	/// How urgently the notification is presented
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub interruption_level: Option<InterruptionLevel>,
+	/// Relevance score between 0 and 1
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub relevance_score: Option<f64>,
+	/// Focus filter criteria
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub filter_criteria: Option<String>,
+}
+
+/// Alert of an APNS notification
+#[derive(Debug, Clone, Serialize)]
+pub struct ApsAlert {
+	/// Title of the alert
+	pub title: String,
+	/// Subtitle of the alert
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub subtitle: Option<String>,
+	/// Body of the alert
+	pub body: String,
 }
 
 impl IntoFirebaseMap for ApnsHeaders {
@@ -330,11 +783,79 @@ impl IntoFirebaseMap for ApnsHeaders {
 	}
 }
 
+/// How urgently a web push is delivered, lower urgencies save battery
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+	/// Only delivered when on power and wifi
+	VeryLow,
+	/// Delivered on power or wifi
+	Low,
+	/// Delivered unless the battery is low
+	Normal,
+	/// Always delivered
+	High,
+}
+
+impl Urgency {
+	/// Value of the `Urgency` header
+	#[must_use]
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::VeryLow => "very-low",
+			Self::Low => "low",
+			Self::Normal => "normal",
+			Self::High => "high",
+		}
+	}
+}
+
+/// Webpush headers
+#[derive(Debug, Clone)]
+pub struct WebpushHeaders {
+	/// Seconds the push service keeps the message for offline clients
+	pub ttl: Option<u64>,
+	/// Urgency of the message
+	pub urgency: Urgency,
+}
+
+impl IntoFirebaseMap for WebpushHeaders {
+	fn as_map(&self) -> FirebaseMap {
+		let mut map = FirebaseMap::new();
+		map.insert("Urgency", self.urgency.as_str());
+		if let Some(ref v) = self.ttl {
+			map.insert("TTL", v);
+		}
+		map
+	}
+}
+
+/// Web notification shown by the Firebase JS SDK
+#[derive(Debug, Serialize, Clone)]
+pub struct WebpushNotification {
+	/// Title of the notification
+	pub title: String,
+	/// Body of the notification
+	pub body: String,
+	/// URL of the notification icon
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub icon: Option<String>,
+	/// URL of the badge
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub badge: Option<String>,
+	/// URL of an image shown in the notification
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub image: Option<String>,
+}
+
 /// Response from the push gateway
 #[derive(Serialize, Debug)]
 pub struct PushGatewayResponse {
 	/// The list of rejected notification push keys
 	pub rejected: Vec<String>,
+	/// Delivery outcome of every device, only returned to trusted homeservers
+	#[serde(rename = "com.famedly.hedwig.outcomes", skip_serializing_if = "Option::is_none")]
+	pub outcomes: Option<Vec<DeliveryOutcome>>,
 }
 
 /// Metrics for prometheus
@@ -352,8 +873,33 @@ pub struct Metrics {
 	pub http_requests_duration_seconds: Histogram<f64>,
 	/// Counter tracking the total number of HTTP requests
 	pub http_requests_total: Counter<u64>,
+	/// Counter for requests rejected by the access control, categorised by
+	/// homeserver and reason
+	pub access_denied: Counter<u64>,
+	/// Histogram tracking the duration of each request to FCM or APNS,
+	/// categorised by transport and app id
+	pub push_duration_seconds: Histogram<f64>,
+	/// Counter for requests to FCM or APNS that failed, categorised by
+	/// transport and normalised reason
+	pub push_errors: Counter<u64>,
+	/// Histogram tracking the number of attempts per device, categorised by
+	/// transport
+	pub push_attempts: Histogram<u64>,
+	/// Counter for pushkeys reported as rejected, categorised by normalised
+	/// reason
+	pub rejected_pushkeys: Counter<u64>,
+	/// Counter for devices using the deprecated `.data_message` app_id
+	/// suffix, categorised by app id
+	pub legacy_devices: Counter<u64>,
 }
 
+/// Histogram buckets for push service latencies in seconds
+const PUSH_DURATION_BUCKETS: [f64; 11] =
+	[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Histogram buckets for the number of attempts per device
+const PUSH_ATTEMPTS_BUCKETS: [f64; 5] = [1.0, 2.0, 3.0, 4.0, 5.0];
+
 impl Metrics {
 	/// Create and register hedwigs prometheus metrics
 	#[must_use]
@@ -377,6 +923,59 @@ impl Metrics {
 				.u64_counter("http.requests")
 				.with_description("Total number of HTTP requests")
 				.build(),
+			access_denied: meter
+				.u64_counter("access.denied")
+				.with_description("Requests rejected by the access control")
+				.build(),
+			push_duration_seconds: meter
+				.f64_histogram("push.duration.seconds")
+				.with_description("Push service request duration in seconds")
+				.with_boundaries(PUSH_DURATION_BUCKETS.to_vec())
+				.build(),
+			push_errors: meter
+				.u64_counter("push.errors")
+				.with_description("Failed push service requests")
+				.build(),
+			push_attempts: meter
+				.u64_histogram("push.attempts")
+				.with_description("Attempts per pushed device")
+				.with_boundaries(PUSH_ATTEMPTS_BUCKETS.to_vec())
+				.build(),
+			rejected_pushkeys: meter
+				.u64_counter("pushkeys.rejected")
+				.with_description("Rejected pushkeys")
+				.build(),
+			legacy_devices: meter
+				.u64_counter("devices.legacy")
+				.with_description("Devices using the deprecated .data_message app_id suffix")
+				.build(),
+		}
+	}
+
+	/// Record a request to a push service
+	pub fn record_send(
+		&self,
+		transport: &NotificationMethod,
+		app_id: &str,
+		duration: Duration,
+		result: Result<(), &HedwigError>,
+	) {
+		self.push_duration_seconds.record(
+			duration.as_secs_f64(),
+			&[
+				KeyValue::new("transport", transport.as_str()),
+				KeyValue::new("app_id", app_id.to_owned()),
+			],
+		);
+
+		if let Err(e) = result {
+			self.push_errors.add(
+				1,
+				&[
+					KeyValue::new("transport", transport.as_str()),
+					KeyValue::new("reason", e.errcode.reason()),
+				],
+			);
 		}
 	}
 }
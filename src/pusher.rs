@@ -19,24 +19,223 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::Arc;
+use std::{
+	collections::BTreeMap,
+	sync::Arc,
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-use a2::{DefaultNotificationBuilder, NotificationBuilder, NotificationOptions};
+use a2::{CollapseId, NotificationOptions, PushType};
 use firebae_cm::{
 	self, AndroidConfig, AndroidMessagePriority, AndroidNotification, ApnsConfig, MessageBody,
+	Visibility, WebpushConfig, WebpushFcmOptions,
 };
-use serde_json::json;
+use serde_json::Value;
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, field::Empty, info_span, warn, Instrument, Span};
 
 use crate::{
-	apns::APNSSender,
+	apns::{APNSSender, ApnsNotification},
+	encryption,
 	error::{ErrCode, HedwigError},
 	fcm::FcmSender,
-	models::{DataMessageType, Device, Notification},
-	settings::Settings,
+	models::{
+		Alert, ApnsHeaders, ApnsMessage, ApnsPayload, Aps, ApsAlert, DataMessageType, Device,
+		Metrics, Notification, NotificationData, NotificationMethod, Priority, ProviderResponse,
+		RenderedNotification, Urgency, WebpushHeaders, WebpushNotification,
+	},
+	reload::Snapshot,
+	settings::{
+		AndroidOverride, CallNotification, DeserializablePushType, NotificationImage,
+		ProviderPrivacy, Settings,
+	},
 };
 
+/// Pushes the notification to the given device through the service it asks
+/// for, recording the request in the given [Metrics]
+///
+/// Devices asking for encrypted notifications get them without any room or
+/// sender metadata.
+pub async fn push_notification(
+	notification: &Notification,
+	device: &Device,
+	config: &Snapshot,
+	metrics: &Metrics,
+) -> Result<ProviderResponse, HedwigError> {
+	// The encrypted copy has no room or event to link to, so its link is
+	// built beforehand and only sent encrypted
+	let link = link(notification, &config.settings);
+	let encrypted = encryption::encrypt_notification(notification, device, link)?;
+	let notification = encrypted.as_ref().unwrap_or(notification);
+
+	match device.notify_via.clone().unwrap_or_default() {
+		NotificationMethod::Apns => {
+			let Some(apns_sender) = &config.apns_sender else {
+				return Err(HedwigError {
+					error: "APNS sender not configured".to_owned(),
+					errcode: ErrCode::APNSNotConfigured,
+				});
+			};
+			push_notification_apns(notification, device, apns_sender, &config.settings, metrics)
+				.await
+		}
+		NotificationMethod::Fcm => {
+			push_notification_fcm(
+				notification,
+				device,
+				&config.fcm_sender,
+				&config.settings,
+				metrics,
+			)
+			.await
+		}
+	}
+}
+
+/// Render the notification for the device, independent of the transport
+///
+/// Badge only notifications are silent. Devices asking for data messages get
+/// the notification data, as do devices pushed to directly through APNs so
+/// their service extension can fetch or decrypt the event. Badge only
+/// notifications only carry the data if configured.
+pub fn render(
+	notification: &Notification,
+	device: &Device,
+	settings: &Settings,
+) -> Result<RenderedNotification, HedwigError> {
+	let badge_only = notification.is_badge_only();
+	let with_data = match device.data_message_type() {
+		// Encrypted notifications are useless without their data
+		DataMessageType::None if notification.ciphertext.is_some() => true,
+		DataMessageType::None if badge_only => settings.hedwig.badge_only_data_message,
+		DataMessageType::None => matches!(device.notify_via, Some(NotificationMethod::Apns)),
+		DataMessageType::Android | DataMessageType::Ios | DataMessageType::Web => true,
+	};
+
+	let link = link(notification, settings);
+	let data = with_data
+		.then(|| notification.data(device, settings.hedwig.provider_privacy))
+		.transpose()?
+		.map(|data| NotificationData { link: link.clone(), ..data });
+
+	Ok(RenderedNotification {
+		alert: (!badge_only).then(|| alert(notification, device, settings)),
+		badge: badge(notification, settings),
+		sound: (!badge_only).then(|| settings.hedwig.notification_sound.clone()),
+		data,
+		link,
+		image: settings
+			.hedwig
+			.notification_image
+			.as_ref()
+			.filter(|_| !badge_only && !device.event_id_only())
+			.filter(|_| settings.hedwig.provider_privacy == ProviderPrivacy::Full)
+			.and_then(|image| image_url(notification.image_uri()?, image)),
+		priority: notification.prio.clone().unwrap_or(Priority::High),
+		collapse_id: settings.hedwig.apns_headers.apns_collapse_id.clone(),
+		expiration: settings.hedwig.apns_headers.apns_expiration,
+	})
+}
+
+/// Alert text for the device, the configured title and body unless the device
+/// uses the full format, the event has a sender and message body and the
+/// privacy level allows forwarding them
+fn alert(notification: &Notification, device: &Device, settings: &Settings) -> Alert {
+	let privacy = settings.hedwig.provider_privacy;
+	notification.alert(device).filter(|_| privacy == ProviderPrivacy::Full).unwrap_or_else(|| {
+		let (title, body) = call_notification(notification, settings).map_or(
+			(&settings.hedwig.notification_title, &settings.hedwig.notification_body),
+			|call| (&call.title, &call.body),
+		);
+
+		Alert { title: fill(title, notification), body: fill(body, notification) }
+	})
+}
+
+/// Deep link for the notification, see [fill_link]
+fn link(notification: &Notification, settings: &Settings) -> Option<String> {
+	fill_link(settings.hedwig.notification_link.as_deref()?, notification)
+}
+
+/// Fill a template pointing to the room or event of the notification, none for
+/// badge only notifications and notifications missing the room or event the
+/// template points to
+fn fill_link(template: &str, notification: &Notification) -> Option<String> {
+	let missing =
+		|placeholder: &str, id: Option<&str>| template.contains(placeholder) && id.is_none();
+
+	if notification.is_badge_only()
+		|| missing("<room_id>", notification.room_id.as_deref())
+		|| missing("<event_id>", notification.event_id.as_deref())
+	{
+		return None;
+	}
+	Some(fill(template, notification))
+}
+
+/// Thumbnail URL on the media proxy for an `mxc://` URI, none for invalid URIs
+fn image_url(uri: &str, image: &NotificationImage) -> Option<String> {
+	let (server_name, media_id) = uri.strip_prefix("mxc://")?.split_once('/')?;
+	let valid_server_name = !server_name.is_empty()
+		&& server_name.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c));
+	let valid_media_id = !media_id.is_empty()
+		&& media_id.chars().all(|c| c.is_ascii_alphanumeric() || "_-".contains(c));
+	if !valid_server_name || !valid_media_id {
+		debug!("Not attaching invalid image URI {uri}");
+		return None;
+	}
+
+	Some(format!(
+		"{}/_matrix/client/v1/media/thumbnail/{server_name}/{media_id}?width={}&height={}&method={}",
+		image.media_proxy.trim_end_matches('/'),
+		image.width,
+		image.height,
+		image.method.as_str(),
+	))
+}
+
+/// Replace the `<count>`, `<missed_calls>`, `<room_id>` and `<event_id>`
+/// placeholders of a notification text
+fn fill(template: &str, notification: &Notification) -> String {
+	let counts = notification.counts.as_ref();
+
+	template
+		.replace("<count>", &counts.and_then(|c| c.unread).unwrap_or_default().to_string())
+		.replace(
+			"<missed_calls>",
+			&counts.and_then(|c| c.missed_calls).unwrap_or_default().to_string(),
+		)
+		.replace("<room_id>", notification.room_id.as_deref().unwrap_or_default())
+		.replace("<event_id>", notification.event_id.as_deref().unwrap_or_default())
+}
+
+/// Badge to set on the app icon, the unread count plus the missed calls if
+/// configured
+fn badge(notification: &Notification, settings: &Settings) -> u32 {
+	let counts = notification.counts.as_ref();
+	let unread = u32::from(counts.and_then(|c| c.unread).unwrap_or_default());
+	let missed_calls = u32::from(counts.and_then(|c| c.missed_calls).unwrap_or_default());
+
+	if settings.hedwig.badge_includes_missed_calls {
+		unread + missed_calls
+	} else {
+		unread
+	}
+}
+
+/// Texts, channel and category configured for the kind of call event the
+/// notification is about, if any
+fn call_notification<'a>(
+	notification: &Notification,
+	settings: &'a Settings,
+) -> Option<&'a CallNotification> {
+	match notification.r#type.as_deref()? {
+		"m.call.invite" => settings.hedwig.call_invite.as_ref(),
+		"m.call.hangup" | "m.call.reject" => settings.hedwig.missed_call.as_ref(),
+		_ => None,
+	}
+}
+
 /// Pushes the FCM notification to the given device
 #[allow(clippy::unused_async)]
 #[allow(clippy::too_many_lines)]
@@ -45,270 +244,585 @@ pub async fn push_notification_fcm(
 	device: &Device,
 	sender: &Mutex<Box<dyn FcmSender + Send + Sync>>,
 	settings: &Settings,
-) -> Result<(), HedwigError> {
+	metrics: &Metrics,
+) -> Result<ProviderResponse, HedwigError> {
 	if !device.app_id.starts_with(&settings.hedwig.app_id) {
 		return Err(HedwigError { error: "Invalid app id!".to_owned(), errcode: ErrCode::BadJson });
 	}
 
-	let count = notification.counts.as_ref().and_then(|c| c.unread).unwrap_or_default();
-
-	let fcm_notification = firebae_cm::Notification {
-		title: Some(settings.hedwig.notification_title.replace("<count>", &count.to_string())),
-		body: Some(
-			settings.hedwig.notification_body.clone().replace("<count>", &count.to_string()),
-		),
-		image: None,
-	};
+	let mut rendered = render(notification, device, settings)?;
 
 	let receiver = firebae_cm::Receiver::Token(device.pushkey.clone());
 	let mut body = MessageBody::new(receiver);
 
 	debug!("Pushing notification to {:?} device", device.data_message_type());
 
+	if let Some(data) = rendered.data.take() {
+		body.data(data)?;
+	}
+
+	let mut android_config = AndroidConfig::new();
+	android_config.direct_boot_ok(false);
+	android_config.priority(match rendered.priority {
+		Priority::High => AndroidMessagePriority::High,
+		Priority::Low => AndroidMessagePriority::Normal,
+	});
+	if let Some(collapse_id) = &rendered.collapse_id {
+		android_config.collapse_key(collapse_id.clone());
+	}
+	if let Some(expiration) = rendered.expiration {
+		android_config.ttl(format!("{expiration}s"));
+	}
+
 	match device.data_message_type() {
 		DataMessageType::Android => {
 			// Used on android for background notification handling
-
-			let mut android_config = AndroidConfig::new();
-			android_config.direct_boot_ok(false);
-			android_config.priority(AndroidMessagePriority::High);
-
-			body.data(notification.data(device)?)?.android(android_config);
+			body.android(android_config);
 		}
 		DataMessageType::None => {
 			// Generic notification following the settings
 			// This codepath runs on old versions of the iOS app also works fine with
 			// android ones
 
-			// If there's no room_id then this is a badge only notification that must not
-			// have any notification content
-			if notification.room_id.is_some() {
-				body.notification(fcm_notification);
+			// Badge only notifications must not have any notification content
+			if let Some(alert) = &rendered.alert {
+				body.notification(fcm_notification(alert, &rendered));
+				android_config.notification(android_notification(
+					notification,
+					device,
+					settings,
+					&rendered,
+				));
 			}
 
-			let mut android_notification = AndroidNotification::new();
-			android_notification
-				.channel_id(settings.hedwig.notification_android.channel_id.clone());
-			android_notification.icon(settings.hedwig.notification_android.icon.clone());
-			android_notification.sound(settings.hedwig.notification_sound.clone());
-			android_notification.tag(settings.hedwig.notification_android.tag.clone());
-			android_notification.click_action(settings.hedwig.notification_click_action.clone());
-
-			// set the values that are not None
-			settings
-				.hedwig
-				.notification_android
-				.color
-				.as_ref()
-				.map(|v| android_notification.color(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.body_loc_key
-				.as_ref()
-				.map(|v| android_notification.body_loc_key(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.body_loc_args
-				.as_ref()
-				.map(|v| android_notification.body_loc_args(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.title_loc_key
-				.as_ref()
-				.map(|v| android_notification.title_loc_key(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.title_loc_args
-				.as_ref()
-				.map(|v| android_notification.title_loc_args(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.ticker
-				.as_ref()
-				.map(|v| android_notification.ticker(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.event_time
-				.as_ref()
-				.map(|v| android_notification.event_time(*v));
-			settings
-				.hedwig
-				.notification_android
-				.default_sound
-				.as_ref()
-				.map(|v| android_notification.default_sound(*v));
-			settings
-				.hedwig
-				.notification_android
-				.vibrate_timings
-				.as_ref()
-				.map(|v| android_notification.vibrate_timings(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.image
-				.as_ref()
-				.map(|v| android_notification.image(v.clone()));
-			settings.hedwig.notification_android.sticky.map(|v| android_notification.sticky(v));
-			settings
-				.hedwig
-				.notification_android
-				.local_only
-				.map(|v| android_notification.local_only(v));
-			settings
-				.hedwig
-				.notification_android
-				.default_vibrate_timings
-				.map(|v| android_notification.default_vibrate_timings(v));
-			settings
-				.hedwig
-				.notification_android
-				.default_light_settings
-				.map(|v| android_notification.default_light_settings(v));
-			settings
-				.hedwig
-				.notification_android
-				.notification_priority
-				.as_ref()
-				.map(|v| android_notification.notification_priority(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.visibility
-				.as_ref()
-				.map(|v| android_notification.visibility(v.clone()));
-			settings
-				.hedwig
-				.notification_android
-				.light_settings
-				.as_ref()
-				.map(|v| android_notification.light_settings(v.clone()));
-
-			let mut android_config = AndroidConfig::new();
-			android_config.notification(android_notification);
-			android_config.direct_boot_ok(false);
-			android_config.priority(AndroidMessagePriority::High);
-
-			let mut ios_config = ApnsConfig::new();
-			ios_config.headers(settings.hedwig.apns_headers.clone())?;
-			let aps = {
-				let mut aps = json!({
-					"badge": count,
-					"sound": settings.hedwig.notification_sound
-				});
-
-				// this is set dynamically as a null value will cause APNS to error
-				if let Some(ref category) = settings.hedwig.apns_payload.category {
-					aps["category"] = json!(category);
-				}
-				if let Some(ref content_available) = settings.hedwig.apns_payload.content_available
-				{
-					aps["content-available"] = json!(content_available);
-				}
-				if let Some(ref mutable_content) = settings.hedwig.apns_payload.mutable_content {
-					aps["mutable-content"] = json!(mutable_content);
-				}
-				aps
-			};
-			ios_config.payload(json!({ "aps": aps }))?;
-
 			body.android(android_config);
-			body.apns(ios_config);
+			body.apns(apns_config(notification, device, settings, &rendered)?);
 		}
 		DataMessageType::Ios => {
 			// Used for background notification handling on iOS, if enabled by the app
 
-			// If there's no room_id then this is a badge only notification that must not
-			// have any notification content
-			if notification.room_id.is_some() {
-				// If apple decide not to run the service extension there needs to be a fallback
-				// notification
-				body.notification(fcm_notification);
+			// If apple decide not to run the service extension there needs to be a fallback
+			// notification
+			if let Some(alert) = &rendered.alert {
+				body.notification(fcm_notification(alert, &rendered));
 			}
 
-			body.data(notification.data(device)?)?;
+			body.apns(apns_config(notification, device, settings, &rendered)?);
+		}
+		DataMessageType::Web => {
+			// Used by web clients registered through Firebase JS
+			if let Some(alert) = &rendered.alert {
+				body.notification(fcm_notification(alert, &rendered));
+			}
 
-			let mut ios_config = ApnsConfig::new();
-			let aps = {
-				let mut aps = json!({
-					"badge": count,
-					"sound": settings.hedwig.notification_sound
-				});
+			body.webpush(webpush_config(notification, settings, &rendered)?);
+		}
+	};
 
-				// this is set dynamically as a null value will cause APNS to error
-				if let Some(ref category) = settings.hedwig.apns_payload.category {
-					aps["category"] = json!(category);
-				}
-				if let Some(ref content_available) = settings.hedwig.apns_payload.content_available
-				{
-					aps["content-available"] = json!(content_available);
-				}
-				if let Some(ref mutable_content) = settings.hedwig.apns_payload.mutable_content {
-					aps["mutable-content"] = json!(mutable_content);
-				}
-				aps
-			};
-			ios_config.payload(json!({ "aps": aps }))?;
+	let span = info_span!(
+		"fcm.send",
+		otel.kind = "client",
+		otel.status_code = Empty,
+		http.response.status_code = Empty,
+		"error.type" = Empty,
+	);
+	let sender = sender.lock().await;
+	let started = Instant::now();
+	let result = sender.send(body).instrument(span.clone()).await;
+	// FCM only answers with a message name on success
+	record_result(&span, result.as_ref().map(|_| 200));
+	metrics.record_send(
+		&NotificationMethod::Fcm,
+		&device.app_id,
+		started.elapsed(),
+		result.as_ref().map(drop),
+	);
+	let name = result?;
+
+	Ok(ProviderResponse::Fcm { name })
+}
 
-			ios_config.headers(settings.hedwig.apns_headers.clone())?;
+/// FCM notification showing the alert and image
+fn fcm_notification(alert: &Alert, rendered: &RenderedNotification) -> firebae_cm::Notification {
+	firebae_cm::Notification {
+		title: Some(alert.title.clone()),
+		body: Some(alert.body.clone()),
+		image: rendered.image.clone(),
+	}
+}
 
-			body.apns(ios_config);
-		}
+/// APNS part of a message relayed through FCM
+fn apns_config(
+	notification: &Notification,
+	device: &Device,
+	settings: &Settings,
+	rendered: &RenderedNotification,
+) -> Result<ApnsConfig, HedwigError> {
+	let mut apns_config = ApnsConfig::new();
+	apns_config.headers(apns_headers(settings, rendered))?;
+	apns_config.payload(serde_json::to_value(apns_message(
+		notification,
+		device,
+		settings,
+		rendered,
+	))?)?;
+
+	Ok(apns_config)
+}
+
+/// Webpush part of a message to a web client
+fn webpush_config(
+	notification: &Notification,
+	settings: &Settings,
+	rendered: &RenderedNotification,
+) -> Result<WebpushConfig, HedwigError> {
+	let webpush = &settings.hedwig.webpush;
+
+	let mut webpush_config = WebpushConfig::new();
+	webpush_config.headers(WebpushHeaders {
+		ttl: webpush.ttl,
+		urgency: webpush.urgency.unwrap_or(match rendered.priority {
+			Priority::High => Urgency::High,
+			Priority::Low => Urgency::Normal,
+		}),
+	})?;
+
+	if let Some(alert) = &rendered.alert {
+		webpush_config.notification(serde_json::to_value(WebpushNotification {
+			title: alert.title.clone(),
+			body: alert.body.clone(),
+			icon: webpush.icon.clone(),
+			badge: webpush.badge.clone(),
+			image: rendered.image.clone(),
+		})?)?;
+	}
+
+	if let Some(link) = webpush
+		.link
+		.as_deref()
+		.and_then(|link| fill_link(link, notification))
+		.or_else(|| rendered.link.clone())
+	{
+		let mut fcm_options = WebpushFcmOptions::new();
+		fcm_options.link(link);
+		webpush_config.fcm_options(fcm_options);
+	}
+
+	Ok(webpush_config)
+}
+
+/// Android notification following the settings and the overrides of the
+/// device, call events may use their own channel
+fn android_notification(
+	notification: &Notification,
+	device: &Device,
+	settings: &Settings,
+	rendered: &RenderedNotification,
+) -> AndroidNotification {
+	let mut android_notification = AndroidNotification::new();
+	android_notification.channel_id(settings.hedwig.notification_android.channel_id.clone());
+	android_notification.icon(settings.hedwig.notification_android.icon.clone());
+	android_notification.sound(settings.hedwig.notification_sound.clone());
+	android_notification.tag(settings.hedwig.notification_android.tag.clone());
+	android_notification.click_action(settings.hedwig.notification_click_action.clone());
+
+	// set the values that are not None
+	settings
+		.hedwig
+		.notification_android
+		.color
+		.as_ref()
+		.map(|v| android_notification.color(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.body_loc_key
+		.as_ref()
+		.map(|v| android_notification.body_loc_key(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.body_loc_args
+		.as_ref()
+		.map(|v| android_notification.body_loc_args(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.title_loc_key
+		.as_ref()
+		.map(|v| android_notification.title_loc_key(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.title_loc_args
+		.as_ref()
+		.map(|v| android_notification.title_loc_args(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.ticker
+		.as_ref()
+		.map(|v| android_notification.ticker(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.event_time
+		.as_ref()
+		.map(|v| android_notification.event_time(*v));
+	settings
+		.hedwig
+		.notification_android
+		.default_sound
+		.as_ref()
+		.map(|v| android_notification.default_sound(*v));
+	settings
+		.hedwig
+		.notification_android
+		.vibrate_timings
+		.as_ref()
+		.map(|v| android_notification.vibrate_timings(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.image
+		.as_ref()
+		.map(|v| android_notification.image(v.clone()));
+	settings.hedwig.notification_android.sticky.map(|v| android_notification.sticky(v));
+	settings.hedwig.notification_android.local_only.map(|v| android_notification.local_only(v));
+	settings
+		.hedwig
+		.notification_android
+		.default_vibrate_timings
+		.map(|v| android_notification.default_vibrate_timings(v));
+	settings
+		.hedwig
+		.notification_android
+		.default_light_settings
+		.map(|v| android_notification.default_light_settings(v));
+	settings
+		.hedwig
+		.notification_android
+		.notification_priority
+		.as_ref()
+		.map(|v| android_notification.notification_priority(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.visibility
+		.as_ref()
+		.map(|v| android_notification.visibility(v.clone()));
+	settings
+		.hedwig
+		.notification_android
+		.light_settings
+		.as_ref()
+		.map(|v| android_notification.light_settings(v.clone()));
+
+	if let Some(image) = &rendered.image {
+		android_notification.image(image.clone());
+	}
+
+	apply_android_overrides(&mut android_notification, device, settings);
+
+	if let Some(channel_id) =
+		call_notification(notification, settings).and_then(|call| call.channel_id.as_ref())
+	{
+		android_notification.channel_id(channel_id.clone());
+	}
+
+	android_notification
+}
+
+/// Apply the Android notification fields the device overrides, skipping the
+/// ones not allowed by the settings or with invalid values
+fn apply_android_overrides(
+	android_notification: &mut AndroidNotification,
+	device: &Device,
+	settings: &Settings,
+) {
+	let Some(overrides) = device.android_overrides() else {
+		return;
 	};
 
-	sender.lock().await.send(body).await?;
+	for (key, value) in overrides {
+		let Some(field) =
+			settings.hedwig.notification_android_overrides.iter().find(|field| field.key() == key)
+		else {
+			debug!("Ignoring Android notification override {key}, not allowed");
+			continue;
+		};
+
+		if let Err(error) = apply_android_override(android_notification, *field, value) {
+			warn!("Ignoring invalid Android notification override {key}: {error}");
+		}
+	}
+}
+
+/// Apply a single validated Android notification override
+fn apply_android_override(
+	android_notification: &mut AndroidNotification,
+	field: AndroidOverride,
+	value: &Value,
+) -> Result<(), String> {
+	/// Longest string accepted for names like channels, icons and tags
+	const MAX_NAME_LENGTH: usize = 256;
+
+	let name = || match value.as_str() {
+		Some(name) if !name.is_empty() && name.len() <= MAX_NAME_LENGTH => Ok(name.to_owned()),
+		_ => Err(format!("expected a string of 1 to {MAX_NAME_LENGTH} bytes")),
+	};
+	let flag = || value.as_bool().ok_or_else(|| "expected a boolean".to_owned());
+
+	match field {
+		AndroidOverride::ChannelId => android_notification.channel_id(name()?),
+		AndroidOverride::Icon => android_notification.icon(name()?),
+		AndroidOverride::Sound => android_notification.sound(name()?),
+		AndroidOverride::Tag => android_notification.tag(name()?),
+		AndroidOverride::Color => {
+			let color = value
+				.as_str()
+				.filter(|color| {
+					color.len() == 7
+						&& color.starts_with('#')
+						&& color[1..].chars().all(|c| c.is_ascii_hexdigit())
+				})
+				.ok_or_else(|| "expected a #rrggbb color".to_owned())?;
+			android_notification.color(color.to_owned())
+		}
+		AndroidOverride::Visibility => android_notification.visibility(
+			serde_json::from_value::<Visibility>(value.clone()).map_err(|e| e.to_string())?,
+		),
+		AndroidOverride::Image => {
+			let image = value
+				.as_str()
+				.filter(|image| image.starts_with("https://"))
+				.ok_or_else(|| "expected an https URL".to_owned())?;
+			android_notification.image(image.to_owned())
+		}
+		AndroidOverride::Sticky => android_notification.sticky(flag()?),
+		AndroidOverride::LocalOnly => android_notification.local_only(flag()?),
+	};
 
 	Ok(())
 }
 
+/// APNS payload of the notification, the same whether it's sent directly or
+/// relayed through FCM
+///
+/// Badge only notifications are silent background pushes, which must neither
+/// play a sound nor be modified by a service extension.
+fn apns_message(
+	notification: &Notification,
+	device: &Device,
+	settings: &Settings,
+	rendered: &RenderedNotification,
+) -> ApnsMessage {
+	let badge = Some(rendered.badge);
+	let Some(alert) = &rendered.alert else {
+		return ApnsMessage {
+			aps: Aps { badge, content_available: Some(1), ..Aps::default() },
+			custom: BTreeMap::new(),
+		};
+	};
+
+	let payload = &settings.hedwig.apns_payload;
+	let interruption_level = if device.highlight() {
+		payload.highlight_interruption_level.or(payload.interruption_level)
+	} else {
+		payload.interruption_level
+	};
+
+	ApnsMessage {
+		aps: Aps {
+			alert: Some(ApsAlert {
+				title: alert.title.clone(),
+				subtitle: payload.subtitle.as_deref().map(|subtitle| fill(subtitle, notification)),
+				body: alert.body.clone(),
+			}),
+			badge,
+			sound: rendered.sound.clone(),
+			thread_id: payload.thread_id.as_deref().map(|thread_id| fill(thread_id, notification)),
+			category: category(notification, settings).cloned(),
+			content_available: payload.content_available,
+			// The service extension downloads the attachment
+			mutable_content: payload.mutable_content.or(rendered.image.as_ref().map(|_| 1)),
+			target_content_id: payload
+				.target_content_id
+				.as_deref()
+				.and_then(|id| fill_link(id, notification))
+				.or_else(|| rendered.link.clone()),
+			interruption_level,
+			relevance_score: payload.relevance_score,
+			filter_criteria: payload.filter_criteria.clone(),
+		},
+		custom: apns_custom(payload, rendered),
+	}
+}
+
+/// Custom keys of the APNS payload, the configured ones, the deep link and the
+/// attachment for the service extension
+fn apns_custom(payload: &ApnsPayload, rendered: &RenderedNotification) -> BTreeMap<String, Value> {
+	let mut custom = payload.custom.clone();
+	if let Some(link) = &rendered.link {
+		custom.insert("link".to_owned(), Value::String(link.clone()));
+	}
+	if let Some(image) = &rendered.image {
+		custom.insert("attachment_url".to_owned(), Value::String(image.clone()));
+	}
+	custom
+}
+
+/// APNS category of the notification, call events may use their own
+fn category<'a>(notification: &Notification, settings: &'a Settings) -> Option<&'a String> {
+	call_notification(notification, settings).and_then(|call| call.category.as_ref()).or(settings
+		.hedwig
+		.apns_payload
+		.category
+		.as_ref())
+}
+
+/// UNIX timestamp at which APNs drops a notification it couldn't deliver
+/// within the given number of seconds
+///
+/// Zero is passed on as is, so APNs only tries delivering right away.
+fn expires_at(expiration: u64) -> u64 {
+	if expiration == 0 {
+		return 0;
+	}
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+		.saturating_add(expiration)
+}
+
+/// Headers sent to APNS, silent notifications are always background pushes
+/// with the priority APNS requires for them
+fn apns_headers(settings: &Settings, rendered: &RenderedNotification) -> ApnsHeaders {
+	let mut headers = settings.hedwig.apns_headers.clone();
+	headers.apns_collapse_id.clone_from(&rendered.collapse_id);
+	headers.apns_expiration = rendered.expiration.map(expires_at);
+	if rendered.alert.is_none() {
+		headers.apns_push_type = DeserializablePushType(PushType::Background);
+	}
+	if rendered.alert.is_none() || matches!(rendered.priority, Priority::Low) {
+		headers.apns_priority = Some("5".to_owned());
+	}
+	headers
+}
+
 /// Pushes a notification to an iOS device using APNs
 pub async fn push_notification_apns(
 	notification: &Notification,
 	device: &Device,
 	sender: &Arc<dyn APNSSender + Send + Sync>,
 	settings: &Settings,
-) -> Result<(), HedwigError> {
+	metrics: &Metrics,
+) -> Result<ProviderResponse, HedwigError> {
 	if !device.app_id.starts_with(&settings.hedwig.app_id) {
 		return Err(HedwigError { error: "Invalid app id!".to_owned(), errcode: ErrCode::BadJson });
 	}
 
-	let count = notification.counts.as_ref().and_then(|c| c.unread).unwrap_or_default();
+	let mut rendered = render(notification, device, settings)?;
 
-	let mut builder = DefaultNotificationBuilder::new()
-		.set_body(settings.hedwig.notification_body.clone().replace("<count>", &count.to_string()))
-		.set_sound(settings.hedwig.notification_sound.clone())
-		.set_title(
-			settings.hedwig.notification_title.clone().replace("<count>", &count.to_string()),
-		)
-		.set_badge(u32::from(count));
-
-	if settings.hedwig.apns_payload.mutable_content.is_some_and(|v| v == 1) {
-		builder = builder.set_mutable_content();
-	}
-	if settings.hedwig.apns_payload.content_available.is_some_and(|v| v == 1) {
-		builder = builder.set_content_available();
-	}
-	if let Some(category) = settings.hedwig.apns_payload.category.clone() {
-		builder = builder.set_category(category);
+	let mut message = apns_message(notification, device, settings, &rendered);
+	// Same keys as the data of messages relayed through FCM
+	if let Some(data) = rendered.data.take() {
+		if let Value::Object(data) = serde_json::to_value(data)? {
+			message.custom.extend(data);
+		}
+		fit_apns_payload(&mut message)?;
 	}
 
-	let options = NotificationOptions {
-		apns_topic: settings.hedwig.apns_headers.apns_topic.clone(),
-		apns_push_type: Some(settings.hedwig.apns_headers.apns_push_type.0),
-		..Default::default()
+	let headers = apns_headers(settings, &rendered);
+	let apns_notification = ApnsNotification {
+		device_token: device.pushkey.clone(),
+		options: NotificationOptions {
+			apns_topic: headers.apns_topic,
+			apns_push_type: Some(headers.apns_push_type.0),
+			apns_priority: (headers.apns_priority.as_deref() == Some("5"))
+				.then_some(a2::Priority::Normal),
+			apns_expiration: headers.apns_expiration,
+			apns_collapse_id: headers.apns_collapse_id.map(CollapseId::new).transpose().map_err(
+				|e| HedwigError {
+					error: format!("Invalid apns_collapse_id: {e}"),
+					errcode: ErrCode::ConfigFailed,
+				},
+			)?,
+			..Default::default()
+		},
+		message,
 	};
 
-	let payload = builder.build(device.pushkey.clone(), options);
-
 	debug!("Pushing notification to {:?} device", device.data_message_type());
 
-	sender.send(payload).await?;
+	let span = info_span!(
+		"apns.send",
+		otel.kind = "client",
+		otel.status_code = Empty,
+		http.response.status_code = Empty,
+		"error.type" = Empty,
+	);
+	let started = Instant::now();
+	let result = sender.send(apns_notification).instrument(span.clone()).await;
+	record_result(&span, result.as_ref().map(|response| response.code));
+	metrics.record_send(
+		&NotificationMethod::Apns,
+		&device.app_id,
+		started.elapsed(),
+		result.as_ref().map(drop),
+	);
+	let response = result?;
+
+	Ok(ProviderResponse::Apns(response))
+}
 
+/// Maximum size of an APNS payload in bytes
+const APNS_MAX_PAYLOAD_SIZE: usize = 4096;
+
+/// Notification data keys dropped from direct APNS payloads going over the size
+/// limit, in order. The keys needed to fetch or decrypt the event are kept.
+const APNS_DROPPABLE_KEYS: &[&str] = &[
+	"content",
+	"devices",
+	"room_name",
+	"room_alias",
+	"sender_display_name",
+	"sender",
+	"type",
+	"user_is_target",
+];
+
+/// Drop notification data keys from the payload until it fits within the APNS
+/// size limit
+fn fit_apns_payload(message: &mut ApnsMessage) -> Result<(), HedwigError> {
+	for key in APNS_DROPPABLE_KEYS {
+		let size = serde_json::to_vec(message)?.len();
+		if size <= APNS_MAX_PAYLOAD_SIZE {
+			return Ok(());
+		}
+		if message.custom.remove(*key).is_some() {
+			debug!("Dropped {key} from APNS payload of {size} bytes");
+		}
+	}
+
+	let size = serde_json::to_vec(message)?.len();
+	if size > APNS_MAX_PAYLOAD_SIZE {
+		warn!("APNS payload of {size} bytes is over the size limit");
+	}
 	Ok(())
 }
+
+/// Record the status code or the normalised error reason of a push service
+/// request on its span
+fn record_result(span: &Span, result: Result<u16, &HedwigError>) {
+	match result {
+		Ok(code) => {
+			span.record("http.response.status_code", code);
+		}
+		Err(e) => {
+			span.record("otel.status_code", "ERROR");
+			span.record("error.type", e.errcode.reason());
+		}
+	}
+}
@@ -0,0 +1,199 @@
+//! Reloading the configuration without restarting
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::WrapErr, Report};
+use tokio::{
+	sync::{mpsc, watch, Mutex},
+	task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+	api::AppState,
+	apns::{APNSSender, APNSSenderImpl},
+	fcm::{FcmSender, FcmSenderImpl},
+	settings::Settings,
+	watcher,
+};
+
+/// How often the config file is checked for modifications
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Senders for the configured push services
+pub type Senders = (Box<dyn FcmSender + Send + Sync>, Option<Box<dyn APNSSender + Send + Sync>>);
+
+/// Settings and senders requests are handled with
+///
+/// A request keeps using the snapshot it started with, even if the
+/// configuration is reloaded in the meantime.
+#[derive(Debug)]
+pub struct Snapshot {
+	/// Hedwig [Settings]
+	pub settings: Arc<Settings>,
+	/// [FcmSender] for communication with Firebase
+	/// Usually [crate::fcm::FcmSenderImpl]
+	pub fcm_sender: Arc<Mutex<Box<dyn FcmSender + Send + Sync>>>,
+	/// [APNSSender] for communication with Apple Push Notification Service
+	/// Usually [crate::apns::APNSSenderImpl]
+	pub apns_sender: Option<Arc<dyn APNSSender + Send + Sync>>,
+}
+
+impl Snapshot {
+	/// Bundle settings and senders into a [Snapshot]
+	#[must_use]
+	pub fn new(
+		fcm_sender: Box<dyn FcmSender + Send + Sync>,
+		apns_sender: Option<Box<dyn APNSSender + Send + Sync>>,
+		settings: Settings,
+	) -> Self {
+		Self {
+			settings: Arc::new(settings),
+			fcm_sender: Arc::new(Mutex::new(fcm_sender)),
+			apns_sender: apns_sender.map(Arc::from),
+		}
+	}
+}
+
+/// Handle to the current [Snapshot], which is swapped atomically on reload
+#[derive(Debug, Clone)]
+pub struct LiveConfig {
+	/// Holds the current snapshot
+	sender: Arc<watch::Sender<Arc<Snapshot>>>,
+}
+
+impl LiveConfig {
+	/// Start out with the given snapshot
+	#[must_use]
+	pub fn new(snapshot: Snapshot) -> Self {
+		Self { sender: Arc::new(watch::Sender::new(Arc::new(snapshot))) }
+	}
+
+	/// The snapshot new requests should be handled with
+	#[must_use]
+	pub fn current(&self) -> Arc<Snapshot> {
+		self.sender.borrow().clone()
+	}
+
+	/// Swap in a new snapshot
+	pub fn replace(&self, snapshot: Snapshot) {
+		self.sender.send_replace(Arc::new(snapshot));
+	}
+}
+
+/// Create the senders for the push services configured in the settings
+pub async fn create_senders(settings: &Settings) -> Result<Senders, Report> {
+	let fcm_sender = FcmSenderImpl::new(&settings.hedwig.fcm_credentials_file_path)
+		.await
+		.wrap_err("Fcm setup failed")?;
+
+	let apns_sender = settings
+		.hedwig
+		.apns_key_file_path
+		.as_ref()
+		.map(|path| {
+			APNSSenderImpl::new(
+				path.clone(),
+				settings.hedwig.apns_team_id.clone(),
+				settings.hedwig.apns_key_id.clone(),
+				settings.hedwig.apns_sandbox,
+			)
+			.wrap_err("APNS authentication failed")
+		})
+		.transpose()?
+		.map(|sender| -> Box<dyn APNSSender + Send + Sync> { Box::new(sender) });
+
+	Ok((Box::new(fcm_sender), apns_sender))
+}
+
+/// Load and validate the settings and build new senders from them
+pub async fn load(config_path: &str) -> Result<Snapshot, Report> {
+	let settings = Settings::load(config_path).wrap_err("Failed to load configuration")?;
+	settings.validate().wrap_err("Invalid configuration")?;
+	let (fcm_sender, apns_sender) = create_senders(&settings).await?;
+
+	Ok(Snapshot::new(fcm_sender, apns_sender, settings))
+}
+
+/// Reload the configuration, keeping the current one if the new one is broken
+pub async fn reload(config_path: &str, app_state: &AppState) {
+	match load(config_path).await {
+		Ok(snapshot) => {
+			let current = app_state.config().current();
+			if snapshot.settings.server.bind_address != current.settings.server.bind_address
+				|| snapshot.settings.server.port != current.settings.server.port
+			{
+				warn!("Changes to the listen address only take effect after a restart");
+			}
+
+			app_state.config().replace(snapshot);
+			info!("Reloaded configuration from {}", config_path);
+			app_state.run_checks().await;
+		}
+		Err(e) => {
+			error!("Failed to reload configuration, keeping the previous one: {:?}", e);
+		}
+	}
+}
+
+/// Spawns a task reloading the configuration on SIGHUP or when the config file
+/// is modified
+pub fn watch(config_path: String, app_state: AppState) -> JoinHandle<()> {
+	let (changed_tx, mut changed_rx) = mpsc::channel(1);
+	watcher::watch_files(vec![PathBuf::from(&config_path)], WATCH_INTERVAL, move || {
+		let changed_tx = changed_tx.clone();
+		async move {
+			// A reload is already pending if the channel is full
+			let _ = changed_tx.try_send(());
+		}
+	});
+
+	tokio::spawn(async move {
+		#[cfg(unix)]
+		let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+			Ok(hangup) => Some(hangup),
+			Err(e) => {
+				error!("Failed to listen for SIGHUP: {}", e);
+				None
+			}
+		};
+
+		loop {
+			#[cfg(unix)]
+			let hangup_received = async {
+				match hangup.as_mut() {
+					Some(hangup) => hangup.recv().await,
+					None => std::future::pending().await,
+				}
+			};
+			#[cfg(not(unix))]
+			let hangup_received = std::future::pending::<Option<()>>();
+
+			tokio::select! {
+				Some(()) = hangup_received => info!("Received SIGHUP, reloading configuration"),
+				Some(()) = changed_rx.recv() => info!("Configuration file changed, reloading"),
+				else => break,
+			}
+
+			reload(&config_path, &app_state).await;
+		}
+	})
+}
@@ -18,15 +18,28 @@
  *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fmt, net::IpAddr, path::PathBuf};
+use std::{
+	fmt,
+	net::IpAddr,
+	path::{Path, PathBuf},
+};
 
 use a2::PushType;
 use config::{Config, ConfigError, Environment, File};
 use firebae_cm::{LightSettings, NotificationPriority, Visibility};
+use ipnet::IpNet;
 use rust_telemetry::config::OtelConfig;
 use serde::{de, Deserialize, Deserializer};
 
-use crate::models::{ApnsHeaders, ApnsPayload};
+use crate::models::{ApnsHeaders, ApnsPayload, Urgency};
+
+/// Parts of config keys marking values that must not be shown, used both for
+/// the `Debug` output of the settings and the redacted config
+const SECRET_KEYS: &[&str] =
+	&["token", "secret", "password", "api_key", "authorization", "apns_team_id", "apns_key_id"];
+
+/// Shown in place of secret values
+const REDACTED: &str = "<redacted>";
 
 /// FCM notification Android-specific configuration
 /// https://firebase.google.com/docs/reference/fcm/rest/v1/projects.messages#androidnotification
@@ -92,14 +105,14 @@ pub struct FcmNotificationAndroid {
 }
 
 /// Hedwig configuration
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct Hedwig {
 	/// Application ID
 	pub app_id: String,
 	/// Maximum amount of attempts hedwig should make
 	pub push_max_retries: i64,
 	/// The text to display in a notification (replaces <count> tag with a
-	/// notification count
+	/// notification count and <missed_calls> with the missed calls
 	pub notification_title: String,
 	/// The text to display as a notification body
 	pub notification_body: String,
@@ -111,9 +124,24 @@ pub struct Hedwig {
 	pub apns_headers: ApnsHeaders,
 	/// Payload sent to APNS
 	pub apns_payload: ApnsPayload,
+	/// Webpush section of FCM messages to web clients
+	#[serde(default)]
+	pub webpush: Webpush,
 
 	/// Action to trigger on the notification click
 	pub notification_click_action: String,
+	/// Deep link opening the room of the notification, replaces the <room_id>
+	/// and <event_id> tags. Sent in the notification data, the Android click
+	/// action stays `notification_click_action`.
+	#[serde(default)]
+	pub notification_link: Option<String>,
+	/// Attach images or avatars to notifications, as thumbnails served by a
+	/// media proxy
+	#[serde(default)]
+	pub notification_image: Option<NotificationImage>,
+	/// How much notification metadata is forwarded to FCM and APNS
+	#[serde(default)]
+	pub provider_privacy: ProviderPrivacy,
 	/// Path to the APNs key file
 	pub apns_key_file_path: Option<PathBuf>,
 	/// Path to the FCM credentials file
@@ -128,6 +156,167 @@ pub struct Hedwig {
 	///
 	/// Defaults to [Settings::DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT]
 	pub notification_request_body_size_limit: u64,
+	/// How to handle devices using the deprecated `.data_message` app_id
+	/// suffix
+	#[serde(default)]
+	pub legacy_data_message: LegacyDataMessage,
+	/// Whether badge only notifications carry the notification data (like the
+	/// room that was read) for devices not getting data messages anyway
+	#[serde(default)]
+	pub badge_only_data_message: bool,
+	/// Whether missed calls are added to the unread count shown as badge
+	#[serde(default)]
+	pub badge_includes_missed_calls: bool,
+	/// Notification for incoming calls (`m.call.invite`)
+	#[serde(default)]
+	pub call_invite: Option<CallNotification>,
+	/// Notification for calls that were hung up or rejected before being
+	/// answered (`m.call.hangup`, `m.call.reject`)
+	#[serde(default)]
+	pub missed_call: Option<CallNotification>,
+	/// Android notification fields devices may override through the
+	/// `android_notification` object of their pusher data, none by default
+	#[serde(default)]
+	pub notification_android_overrides: Vec<AndroidOverride>,
+}
+
+/// Texts, channel and category used for notifications about a call instead of
+/// the default ones
+#[derive(Debug, Deserialize, Clone)]
+pub struct CallNotification {
+	/// The text to display in a notification (replaces <count> and
+	/// <missed_calls> tags with the notification counts)
+	pub title: String,
+	/// The text to display as a notification body
+	pub body: String,
+	/// Android notification channel
+	pub channel_id: Option<String>,
+	/// APNS category
+	pub category: Option<String>,
+}
+
+/// Webpush configuration for web clients registered through Firebase JS
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Webpush {
+	/// Seconds the push service keeps the message for offline clients
+	pub ttl: Option<u64>,
+	/// Urgency of the message, derived from the notification priority if
+	/// unset
+	pub urgency: Option<Urgency>,
+	/// URL of the notification icon
+	pub icon: Option<String>,
+	/// URL of the monochrome badge shown when there isn't enough space for the
+	/// notification
+	pub badge: Option<String>,
+	/// Link opened on click, replaces the <room_id> and <event_id> tags to
+	/// deep link to the room, `notification_link` is used if unset
+	pub link: Option<String>,
+}
+
+/// Images attached to notifications, `mxc://` URIs are rewritten to
+/// authenticated media thumbnail URLs on the media proxy
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotificationImage {
+	/// Base URL of the media proxy, which serves
+	/// `/_matrix/client/v1/media/thumbnail` without authentication
+	pub media_proxy: String,
+	/// Width of the thumbnail in pixels
+	#[serde(default = "NotificationImage::default_size")]
+	pub width: u32,
+	/// Height of the thumbnail in pixels
+	#[serde(default = "NotificationImage::default_size")]
+	pub height: u32,
+	/// How the thumbnail is resized
+	#[serde(default)]
+	pub method: ThumbnailMethod,
+}
+
+impl NotificationImage {
+	/// Default width and height of the thumbnail
+	pub const DEFAULT_SIZE: u32 = 256;
+
+	/// Default for [NotificationImage::width] and [NotificationImage::height]
+	const fn default_size() -> u32 {
+		Self::DEFAULT_SIZE
+	}
+}
+
+/// How thumbnails are resized
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailMethod {
+	/// Cropped to the exact size
+	#[default]
+	Crop,
+	/// Scaled to fit within the size, keeping the aspect ratio
+	Scale,
+}
+
+impl ThumbnailMethod {
+	/// Value of the `method` query parameter
+	#[must_use]
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::Crop => "crop",
+			Self::Scale => "scale",
+		}
+	}
+}
+
+/// Android notification field a device may override through its pusher data
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AndroidOverride {
+	/// ID of the android channel, like a per-account channel
+	ChannelId,
+	/// Notification icon
+	Icon,
+	/// Icon color in #rrggbb format, like an accent color per account
+	Color,
+	/// Notification sound
+	Sound,
+	/// Notification tag
+	Tag,
+	/// Visibility on the lock screen
+	Visibility,
+	/// HTTPS URL of an image to display
+	Image,
+	/// Whether the notification persists when clicked
+	Sticky,
+	/// Whether the notification is only shown on this device
+	LocalOnly,
+}
+
+impl AndroidOverride {
+	/// Key of the field in the `android_notification` pusher data
+	#[must_use]
+	pub const fn key(self) -> &'static str {
+		match self {
+			Self::ChannelId => "channel_id",
+			Self::Icon => "icon",
+			Self::Color => "color",
+			Self::Sound => "sound",
+			Self::Tag => "tag",
+			Self::Visibility => "visibility",
+			Self::Image => "image",
+			Self::Sticky => "sticky",
+			Self::LocalOnly => "local_only",
+		}
+	}
+}
+
+/// How to handle devices using the deprecated `.data_message` app_id suffix
+/// instead of `data_message: android` in the pusher data
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LegacyDataMessage {
+	/// Push to them like before
+	Accept,
+	/// Push to them, but log a warning
+	#[default]
+	Warn,
+	/// Report their pushkeys as rejected
+	Reject,
 }
 
 /// We need this to implement the Deserialize trait for PushType
@@ -172,6 +361,164 @@ pub struct Server {
 	pub port: u16,
 	/// IP address the server is listening on
 	pub bind_address: IpAddr,
+	/// Access control for the notify endpoint
+	#[serde(default)]
+	pub access: Access,
+	/// Serve HTTPS directly instead of plain HTTP
+	pub tls: Option<Tls>,
+	/// Seconds to wait for in-flight requests to finish on shutdown
+	///
+	/// Defaults to [Settings::DEFAULT_SHUTDOWN_GRACE_PERIOD]
+	pub shutdown_grace_period: u64,
+	/// Seconds between the readiness endpoint starting to fail and the
+	/// listener no longer accepting connections on shutdown, giving load
+	/// balancers time to stop routing requests here
+	///
+	/// Defaults to [Settings::DEFAULT_SHUTDOWN_DELAY]
+	pub shutdown_delay: u64,
+	/// Seconds between checks of the push services for the readiness endpoint
+	///
+	/// Defaults to [Settings::DEFAULT_READINESS_CHECK_INTERVAL]
+	pub readiness_check_interval: u64,
+	/// Admin API, disabled if not set
+	pub admin: Option<Admin>,
+}
+
+/// Admin API listener configuration
+#[derive(Deserialize, Clone)]
+pub struct Admin {
+	/// Port the admin API is listening on
+	pub port: u16,
+	/// IP address the admin API is listening on
+	pub bind_address: IpAddr,
+	/// Bearer token required for all admin requests
+	pub token: String,
+}
+
+impl fmt::Debug for Admin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		debug_redacted(
+			f,
+			"Admin",
+			&[("port", &self.port), ("bind_address", &self.bind_address), ("token", &self.token)],
+		)
+	}
+}
+
+/// TLS listener configuration
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tls {
+	/// Path to the PEM encoded certificate chain
+	pub certificate_file_path: PathBuf,
+	/// Path to the PEM encoded private key
+	pub key_file_path: PathBuf,
+	/// Path to the PEM encoded CA certificates used to verify client
+	/// certificates. Enables mutual TLS when set.
+	pub client_ca_file_path: Option<PathBuf>,
+	/// Whether connections without a valid client certificate are refused
+	#[serde(default)]
+	pub client_auth_required: bool,
+	/// Interval in seconds in which the certificate files are checked for
+	/// changes
+	#[serde(default = "Tls::default_reload_interval")]
+	pub reload_interval: u64,
+}
+
+impl Tls {
+	/// Default interval for checking the certificate files for changes
+	pub const DEFAULT_RELOAD_INTERVAL: u64 = 30;
+
+	/// Default for [Tls::reload_interval]
+	const fn default_reload_interval() -> u64 {
+		Self::DEFAULT_RELOAD_INTERVAL
+	}
+}
+
+/// Access control for the notify endpoint
+///
+/// With the default (empty) configuration anyone who can reach the port is
+/// allowed to push.
+#[derive(Debug, Deserialize, Default)]
+pub struct Access {
+	/// Networks that are allowed to call the notify endpoint. An empty list
+	/// allows any address.
+	#[serde(default)]
+	pub allowed_networks: Vec<IpNet>,
+	/// Homeservers that are allowed to call the notify endpoint. If this is
+	/// not empty, every request has to authenticate as one of them.
+	#[serde(default)]
+	pub homeservers: Vec<HomeserverAccess>,
+}
+
+/// Credentials of a homeserver that is allowed to push
+#[derive(Deserialize)]
+pub struct HomeserverAccess {
+	/// Name of the homeserver, used in logs and metrics
+	pub name: String,
+	/// Shared secret the homeserver sends as a bearer token
+	pub token: Option<String>,
+	/// SHA-256 fingerprints of the client certificates the homeserver
+	/// authenticates with when mutual TLS is used
+	#[serde(default)]
+	pub client_certificate_fingerprints: Vec<String>,
+	/// Whether the notify response to this homeserver includes the per-device
+	/// delivery outcomes
+	#[serde(default)]
+	pub trusted: bool,
+}
+
+impl fmt::Debug for HomeserverAccess {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		debug_redacted(
+			f,
+			"HomeserverAccess",
+			&[
+				("name", &self.name),
+				("token", &self.token),
+				("client_certificate_fingerprints", &self.client_certificate_fingerprints),
+				("trusted", &self.trusted),
+			],
+		)
+	}
+}
+
+impl fmt::Debug for Hedwig {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		debug_redacted(
+			f,
+			"Hedwig",
+			&[
+				("app_id", &self.app_id),
+				("push_max_retries", &self.push_max_retries),
+				("notification_title", &self.notification_title),
+				("notification_body", &self.notification_body),
+				("notification_sound", &self.notification_sound),
+				("notification_android", &self.notification_android),
+				("apns_headers", &self.apns_headers),
+				("apns_payload", &self.apns_payload),
+				("webpush", &self.webpush),
+				("notification_click_action", &self.notification_click_action),
+				("notification_link", &self.notification_link),
+				("notification_image", &self.notification_image),
+				("provider_privacy", &self.provider_privacy),
+				("apns_key_file_path", &self.apns_key_file_path),
+				("fcm_credentials_file_path", &self.fcm_credentials_file_path),
+				("apns_team_id", &self.apns_team_id),
+				("apns_key_id", &self.apns_key_id),
+				("apns_sandbox", &self.apns_sandbox),
+				(
+					"notification_request_body_size_limit",
+					&self.notification_request_body_size_limit,
+				),
+				("legacy_data_message", &self.legacy_data_message),
+				("badge_only_data_message", &self.badge_only_data_message),
+				("badge_includes_missed_calls", &self.badge_includes_missed_calls),
+				("call_invite", &self.call_invite),
+				("missed_call", &self.missed_call),
+				("notification_android_overrides", &self.notification_android_overrides),
+			],
+		)
+	}
 }
 
 /// Log settings
@@ -179,6 +526,46 @@ pub struct Server {
 pub struct Log {
 	/// Log level (DEBUG, INFO, ERROR etc.)
 	pub level: String,
+	/// How much notification metadata ends up in logs and traces
+	#[serde(default)]
+	pub privacy: Privacy,
+}
+
+/// How much notification metadata ends up in logs and traces
+///
+/// Pushkeys are only ever recorded hashed, notification content and encrypted
+/// payloads never.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+	/// Only the number of devices, the homeserver and the transport of each
+	/// push
+	Minimal,
+	/// Additionally the event id, notification type, priority and the app_id
+	/// of each push
+	#[default]
+	Standard,
+	/// Additionally the room, sender and hashed pushkeys
+	Full,
+}
+
+/// How much notification metadata is forwarded to the push services
+///
+/// Encrypted payloads are always forwarded, devices using the `event_id_only`
+/// format never get more than the standard level.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderPrivacy {
+	/// Only the event and room id and the counts
+	Minimal,
+	/// Additionally the priority, event type, whether the user is the target
+	/// of a member event and the device without its tweaks and pushkey
+	/// timestamp
+	Standard,
+	/// Everything the pusher format allows, including the sender, room name
+	/// and alias and content, which are also shown in the alert and image
+	#[default]
+	Full,
 }
 
 /// Main settings struct
@@ -195,11 +582,23 @@ pub struct Settings {
 	pub hedwig: Hedwig,
 	/// rust-telemetry settings
 	pub telemetry: OtelConfig,
+	/// The merged config file and environment these settings were loaded
+	/// from, with secrets redacted, or null if they weren't loaded from a file
+	#[serde(skip)]
+	pub redacted: serde_json::Value,
 }
 
 impl Settings {
 	/// Default length limit for the matrix push notifications
 	pub const DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT: u64 = 15000;
+	/// Default time in seconds to wait for in-flight requests on shutdown
+	pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 30;
+	/// Default time in seconds between failing readiness and shutting down
+	pub const DEFAULT_SHUTDOWN_DELAY: u64 = 0;
+	/// Default time in seconds between readiness checks
+	pub const DEFAULT_READINESS_CHECK_INTERVAL: u64 = 60;
+	/// Longest collapse id APNs accepts, in bytes
+	pub const MAX_COLLAPSE_ID_LENGTH: usize = 64;
 	/// Hedwig default log level
 	pub const DEFAULT_LOG_LEVEL: &'static str = "INFO";
 	/// Config filename
@@ -207,6 +606,27 @@ impl Settings {
 
 	/// Load settings from file
 	pub fn load(filename: &str) -> Result<Self, ConfigError> {
+		let config = Self::merged(filename)?;
+		let mut redacted: serde_json::Value = config.clone().try_deserialize()?;
+		redact(&mut redacted);
+		Ok(Self { redacted, ..config.try_deserialize()? })
+	}
+
+	/// Load the settings from file and environment without interpreting them,
+	/// for showing the effective configuration
+	pub fn load_raw(filename: &str) -> Result<serde_json::Value, ConfigError> {
+		Self::merged(filename)?.try_deserialize()
+	}
+
+	/// Like [Settings::load_raw], with all secrets replaced
+	pub fn load_redacted(filename: &str) -> Result<serde_json::Value, ConfigError> {
+		let mut settings = Self::load_raw(filename)?;
+		redact(&mut settings);
+		Ok(settings)
+	}
+
+	/// Merge the config file, `PUSHGW__` environment variables and defaults
+	fn merged(filename: &str) -> Result<Config, ConfigError> {
 		Config::builder()
 			.add_source(File::with_name(filename).required(false))
 			.add_source(Environment::with_prefix("pushgw").prefix_separator("__").separator("__"))
@@ -215,7 +635,137 @@ impl Settings {
 				"hedwig.notification_request_body_size_limit",
 				Self::DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT,
 			)?
-			.build()?
-			.try_deserialize()
+			.set_default("server.shutdown_grace_period", Self::DEFAULT_SHUTDOWN_GRACE_PERIOD)?
+			.set_default("server.shutdown_delay", Self::DEFAULT_SHUTDOWN_DELAY)?
+			.set_default("server.readiness_check_interval", Self::DEFAULT_READINESS_CHECK_INTERVAL)?
+			.build()
+	}
+
+	/// Check for mistakes that only surface once a push is sent or a
+	/// connection is accepted
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		require_file("FCM credentials", &self.hedwig.fcm_credentials_file_path)?;
+		require_interval("server.readiness_check_interval", self.server.readiness_check_interval)?;
+
+		if let Some(path) = &self.hedwig.apns_key_file_path {
+			require_file("APNS key", path)?;
+			if self.hedwig.apns_team_id.is_empty() || self.hedwig.apns_key_id.is_empty() {
+				return Err(ConfigError::Message(
+					"apns_team_id and apns_key_id are required when an APNS key is configured"
+						.to_owned(),
+				));
+			}
+		}
+
+		for homeserver in &self.server.access.homeservers {
+			if homeserver.token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+				return Err(ConfigError::Message(format!(
+					"The token of homeserver {} must not be empty",
+					homeserver.name
+				)));
+			}
+			if homeserver.token.is_none() && homeserver.client_certificate_fingerprints.is_empty() {
+				return Err(ConfigError::Message(format!(
+					"Homeserver {} needs a token or client certificate fingerprints",
+					homeserver.name
+				)));
+			}
+		}
+
+		if self.server.admin.as_ref().is_some_and(|admin| admin.token.trim().is_empty()) {
+			return Err(ConfigError::Message("server.admin.token must not be empty".to_owned()));
+		}
+
+		if self
+			.hedwig
+			.apns_headers
+			.apns_collapse_id
+			.as_ref()
+			.is_some_and(|id| id.len() > Self::MAX_COLLAPSE_ID_LENGTH)
+		{
+			return Err(ConfigError::Message(format!(
+				"hedwig.apns_headers.apns_collapse_id must be at most {} bytes",
+				Self::MAX_COLLAPSE_ID_LENGTH
+			)));
+		}
+
+		let apns_payload = &self.hedwig.apns_payload;
+		if apns_payload.relevance_score.is_some_and(|score| !(0.0..=1.0).contains(&score)) {
+			return Err(ConfigError::Message(
+				"hedwig.apns_payload.relevance_score must be between 0 and 1".to_owned(),
+			));
+		}
+		if apns_payload.custom.contains_key("aps") {
+			return Err(ConfigError::Message(
+				"hedwig.apns_payload.custom must not contain aps".to_owned(),
+			));
+		}
+
+		if let Some(tls) = &self.server.tls {
+			require_file("TLS certificate", &tls.certificate_file_path)?;
+			require_file("TLS key", &tls.key_file_path)?;
+			require_interval("server.tls.reload_interval", tls.reload_interval)?;
+			if let Some(path) = &tls.client_ca_file_path {
+				require_file("TLS client CA", path)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Replace the values of all keys that look like they hold secrets
+pub fn redact(value: &mut serde_json::Value) {
+	match value {
+		serde_json::Value::Object(map) => {
+			for (key, value) in map.iter_mut() {
+				if is_secret(key) && !value.is_null() {
+					*value = serde_json::Value::String(REDACTED.to_owned());
+				} else {
+					redact(value);
+				}
+			}
+		}
+		serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+		_ => {}
+	}
+}
+
+/// Whether the value of the given config key must not be shown
+fn is_secret(key: &str) -> bool {
+	let key = key.to_lowercase();
+	SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+/// Writes the `Debug` output of a struct, with the fields [is_secret] matches
+/// redacted
+fn debug_redacted(
+	f: &mut fmt::Formatter<'_>,
+	name: &str,
+	fields: &[(&str, &dyn fmt::Debug)],
+) -> fmt::Result {
+	let mut debug = f.debug_struct(name);
+	for &(key, value) in fields {
+		debug.field(key, if is_secret(key) { &REDACTED } else { value });
+	}
+	debug.finish()
+}
+
+/// Fails if the given interval in seconds is zero, which would stop the
+/// periodic task using it
+fn require_interval(what: &str, seconds: u64) -> Result<(), ConfigError> {
+	if seconds == 0 {
+		Err(ConfigError::Message(format!("{what} must be at least one second")))
+	} else {
+		Ok(())
+	}
+}
+
+/// Fails if the given path is not an existing file
+fn require_file(what: &str, path: &Path) -> Result<(), ConfigError> {
+	if path.is_file() {
+		Ok(())
+	} else {
+		Err(ConfigError::Message(format!("{what} file {} does not exist", path.display())))
 	}
 }
@@ -0,0 +1,99 @@
+//! Graceful shutdown handling
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+use tracing::error;
+
+/// Shared flag telling all parts of Hedwig that a shutdown is in progress
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+	/// Set to `true` once the shutdown was triggered
+	sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self { sender: Arc::new(watch::Sender::new(false)) }
+	}
+}
+
+impl Shutdown {
+	/// Start shutting down
+	pub fn trigger(&self) {
+		self.sender.send_replace(true);
+	}
+
+	/// Whether the shutdown was triggered
+	#[must_use]
+	pub fn is_triggered(&self) -> bool {
+		*self.sender.borrow()
+	}
+
+	/// Resolves once the shutdown was triggered
+	pub async fn triggered(&self) {
+		let mut receiver = self.sender.subscribe();
+		// The sender lives as long as `self`, so this can't fail
+		let _ = receiver.wait_for(|triggered| *triggered).await;
+	}
+
+	/// Resolves the given delay after the shutdown was triggered, when the
+	/// listeners should stop accepting connections
+	///
+	/// Readiness fails as soon as the shutdown is triggered, the delay gives
+	/// load balancers time to notice before connections are refused.
+	pub async fn draining(&self, delay: Duration) {
+		self.triggered().await;
+		tokio::time::sleep(delay).await;
+	}
+}
+
+/// Resolves once SIGTERM or SIGINT is received
+pub async fn signal() {
+	let interrupt = async {
+		if let Err(e) = tokio::signal::ctrl_c().await {
+			error!("Failed to listen for SIGINT: {}", e);
+			std::future::pending::<()>().await;
+		}
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		use tokio::signal::unix::{signal, SignalKind};
+
+		match signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				terminate.recv().await;
+			}
+			Err(e) => {
+				error!("Failed to listen for SIGTERM: {}", e);
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		() = interrupt => {},
+		() = terminate => {},
+	}
+}
@@ -0,0 +1,216 @@
+//! TLS listener with certificate hot-reload and client certificate support
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+	fmt::Write,
+	fs::File,
+	io::{self, BufReader},
+	path::Path,
+	sync::Arc,
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use axum::http::Request;
+use axum_server::{
+	accept::Accept,
+	tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use color_eyre::{
+	eyre::{eyre, WrapErr},
+	Report,
+};
+use futures::future::BoxFuture;
+use rustls::{
+	crypto::aws_lc_rs,
+	pki_types::{CertificateDer, PrivateKeyDer},
+	server::WebPkiClientVerifier,
+	RootCertStore, ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Service;
+use tracing::{error, info};
+
+use crate::{access::ClientCertificate, settings::Tls, watcher};
+
+/// Reads all certificates from a PEM file
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Report> {
+	let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+	rustls_pemfile::certs(&mut BufReader::new(file))
+		.collect::<Result<Vec<_>, _>>()
+		.wrap_err_with(|| format!("Failed to read certificates from {}", path.display()))
+}
+
+/// Reads the first private key from a PEM file
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Report> {
+	let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+	rustls_pemfile::private_key(&mut BufReader::new(file))
+		.wrap_err_with(|| format!("Failed to read private key from {}", path.display()))?
+		.ok_or_else(|| eyre!("No private key found in {}", path.display()))
+}
+
+/// Builds the rustls server configuration from the certificate files
+///
+/// # Errors
+///
+/// Fails if any of the files can't be read or don't contain valid
+/// certificates and keys.
+pub fn server_config(tls: &Tls) -> Result<ServerConfig, Report> {
+	let provider = Arc::new(aws_lc_rs::default_provider());
+	let builder = ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()?;
+
+	let builder = match &tls.client_ca_file_path {
+		Some(path) => {
+			let mut roots = RootCertStore::empty();
+			for certificate in load_certificates(path)? {
+				roots.add(certificate)?;
+			}
+
+			let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+			let verifier = if tls.client_auth_required {
+				verifier.build()?
+			} else {
+				verifier.allow_unauthenticated().build()?
+			};
+			builder.with_client_cert_verifier(verifier)
+		}
+		None => builder.with_no_client_auth(),
+	};
+
+	let mut config = builder.with_single_cert(
+		load_certificates(&tls.certificate_file_path)?,
+		load_private_key(&tls.key_file_path)?,
+	)?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(config)
+}
+
+/// Reloads the TLS configuration whenever one of the certificate files changes
+///
+/// If the new files can't be loaded the previous configuration stays active.
+pub fn watch_certificates(tls: &Tls, config: RustlsConfig) {
+	let paths = [Some(&tls.certificate_file_path), Some(&tls.key_file_path)]
+		.into_iter()
+		.chain([tls.client_ca_file_path.as_ref()])
+		.flatten()
+		.cloned()
+		.collect();
+
+	let tls = tls.clone();
+	watcher::watch_files(paths, Duration::from_secs(tls.reload_interval), move || {
+		let reloaded = server_config(&tls);
+		let config = config.clone();
+		async move {
+			match reloaded {
+				Ok(server_config) => {
+					config.reload_from_config(Arc::new(server_config));
+					info!("Reloaded TLS certificates");
+				}
+				Err(e) => {
+					error!("Failed to reload TLS certificates, keeping the previous ones: {:?}", e);
+				}
+			}
+		}
+	});
+}
+
+/// Hex encoded SHA-256 fingerprint of a certificate
+fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+	Sha256::digest(certificate.as_ref()).iter().fold(String::new(), |mut hex, byte| {
+		let _ = write!(hex, "{byte:02x}");
+		hex
+	})
+}
+
+/// TLS acceptor that makes the client certificate available to the request
+/// handlers as [ClientCertificate] extension
+#[derive(Debug, Clone)]
+pub struct ClientCertificateAcceptor {
+	/// The acceptor doing the TLS handshake
+	inner: RustlsAcceptor,
+}
+
+impl ClientCertificateAcceptor {
+	/// Create a new acceptor using the given TLS configuration
+	#[must_use]
+	pub fn new(config: RustlsConfig) -> Self {
+		Self { inner: RustlsAcceptor::new(config) }
+	}
+}
+
+impl<I, S> Accept<I, S> for ClientCertificateAcceptor
+where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	S: Send + 'static,
+{
+	type Stream = TlsStream<I>;
+	type Service = ClientCertificateService<S>;
+	type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+	fn accept(&self, stream: I, service: S) -> Self::Future {
+		let handshake = self.inner.accept(stream, service);
+
+		Box::pin(async move {
+			let (stream, service) = handshake.await?;
+			let certificate = stream
+				.get_ref()
+				.1
+				.peer_certificates()
+				.and_then(<[_]>::first)
+				.map(|certificate| ClientCertificate { fingerprint: fingerprint(certificate) });
+
+			Ok((stream, ClientCertificateService { inner: service, certificate }))
+		})
+	}
+}
+
+/// Service inserting the client certificate of the connection into each
+/// request
+#[derive(Debug, Clone)]
+pub struct ClientCertificateService<S> {
+	/// Inner service
+	inner: S,
+	/// The certificate the client authenticated with, if any
+	certificate: Option<ClientCertificate>,
+}
+
+impl<S, B> Service<Request<B>> for ClientCertificateService<S>
+where
+	S: Service<Request<B>>,
+{
+	type Response = S::Response;
+	type Error = S::Error;
+	type Future = S::Future;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, mut req: Request<B>) -> Self::Future {
+		if let Some(certificate) = &self.certificate {
+			req.extensions_mut().insert(certificate.clone());
+		}
+		self.inner.call(req)
+	}
+}
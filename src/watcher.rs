@@ -0,0 +1,68 @@
+//! Polls files for changes so they can be reloaded without a restart
+
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+	future::Future,
+	path::PathBuf,
+	time::{Duration, SystemTime},
+};
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+/// Modification times of the given files, `None` for files that can't be read
+async fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+	let mut times = Vec::with_capacity(paths.len());
+	for path in paths {
+		times.push(tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok());
+	}
+	times
+}
+
+/// Spawns a task calling `on_change` whenever one of the files is modified
+///
+/// Symlinks are followed, so swapping a symlink to a new file (as done for
+/// Kubernetes secrets) counts as a modification.
+pub fn watch_files<F, Fut>(
+	paths: Vec<PathBuf>,
+	interval: Duration,
+	mut on_change: F,
+) -> JoinHandle<()>
+where
+	F: FnMut() -> Fut + Send + 'static,
+	Fut: Future<Output = ()> + Send,
+{
+	tokio::spawn(async move {
+		let mut last_modified = modification_times(&paths).await;
+
+		let mut ticker = tokio::time::interval(interval);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		// The first tick completes immediately
+		ticker.tick().await;
+
+		loop {
+			ticker.tick().await;
+			let modified = modification_times(&paths).await;
+			if modified != last_modified {
+				last_modified = modified;
+				on_change().await;
+			}
+		}
+	})
+}
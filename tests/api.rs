@@ -17,14 +17,14 @@
  */
 //! Tests for the api server.
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
-use a2::{request::payload::Payload, PushType};
+use a2::PushType;
 use async_trait::async_trait;
 use firebae_cm::MessageBody;
 use matrix_hedwig::{
 	api::run_server,
-	apns::APNSSender,
+	apns::{APNSSender, ApnsNotification, ApnsResponse},
 	error::HedwigError,
 	fcm::FcmSender,
 	models::{ApnsHeaders, ApnsPayload},
@@ -48,15 +48,24 @@ struct FakeAPNSSender {}
 
 #[async_trait]
 impl APNSSender for FakeAPNSSender {
-	async fn send(&self, _payload: Payload) -> Result<(), HedwigError> {
-		Ok(())
+	async fn send(&self, _payload: ApnsNotification) -> Result<ApnsResponse, HedwigError> {
+		Ok(ApnsResponse { apns_id: None, code: 200 })
 	}
 }
 
 fn create_test_settings(port: u16) -> Settings {
-	let log = settings::Log { level: "DEBUG".to_owned() };
+	let log = settings::Log { level: "DEBUG".to_owned(), privacy: settings::Privacy::Full };
 
-	let server = settings::Server { port, bind_address: [127, 0, 0, 1].into() };
+	let server = settings::Server {
+		port,
+		bind_address: [127, 0, 0, 1].into(),
+		access: settings::Access::default(),
+		tls: None,
+		shutdown_grace_period: Settings::DEFAULT_SHUTDOWN_GRACE_PERIOD,
+		shutdown_delay: Settings::DEFAULT_SHUTDOWN_DELAY,
+		readiness_check_interval: Settings::DEFAULT_READINESS_CHECK_INTERVAL,
+		admin: None,
+	};
 
 	let hedwig = settings::Hedwig {
 		app_id: "com.test.app".to_owned(),
@@ -87,8 +96,18 @@ fn create_test_settings(port: u16) -> Settings {
 			image: None,
 		},
 		notification_click_action: "TEST_CLICK".to_owned(),
+		notification_link: None,
+		notification_image: None,
+		provider_privacy: settings::ProviderPrivacy::Full,
 		notification_request_body_size_limit:
 			Settings::DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT,
+		legacy_data_message: settings::LegacyDataMessage::Warn,
+		badge_only_data_message: false,
+		badge_includes_missed_calls: false,
+		call_invite: None,
+		missed_call: None,
+		notification_android_overrides: vec![],
+		webpush: settings::Webpush::default(),
 		apns_headers: ApnsHeaders {
 			apns_push_type: DeserializablePushType(PushType::Background),
 			apns_topic: Some("app.bundle.id".to_owned()),
@@ -101,6 +120,14 @@ fn create_test_settings(port: u16) -> Settings {
 			category: None,
 			content_available: None,
 			mutable_content: Some(1),
+			interruption_level: None,
+			highlight_interruption_level: None,
+			relevance_score: None,
+			thread_id: None,
+			subtitle: None,
+			target_content_id: None,
+			filter_criteria: None,
+			custom: BTreeMap::new(),
 		},
 		apns_key_file_path: None,
 		fcm_credentials_file_path: PathBuf::from(""),
@@ -108,7 +135,13 @@ fn create_test_settings(port: u16) -> Settings {
 		apns_key_id: "KEY_ID".to_owned(),
 		apns_sandbox: false,
 	};
-	Settings { log, server, hedwig, telemetry: OtelConfig::default() }
+	Settings {
+		log,
+		server,
+		hedwig,
+		telemetry: OtelConfig::default(),
+		redacted: serde_json::Value::Null,
+	}
 }
 
 #[tokio::test]
@@ -116,9 +149,9 @@ async fn server_starts_successfully() -> Result<(), Box<dyn std::error::Error>>
 	// Use a high port that's unlikely to be in use
 	let settings = create_test_settings(0);
 	let fcm_sender: Box<dyn FcmSender + Send + Sync> = Box::new(FakeFcmSender);
-	let apns_sender = FakeAPNSSender {};
+	let apns_sender: Box<dyn APNSSender + Send + Sync> = Box::new(FakeAPNSSender {});
 
-	let server_handle = tokio::spawn(run_server(settings, fcm_sender, Some(apns_sender)));
+	let server_handle = tokio::spawn(run_server(settings, fcm_sender, Some(apns_sender), None));
 
 	// wait in case an error occurs during startup
 	time::sleep(time::Duration::from_secs(1)).await;
@@ -59,3 +59,18 @@ fn apns_sender_create_production() {
 
 	assert!(result.is_ok());
 }
+
+#[test]
+fn apns_sender_debug_redacted() {
+	let sender = APNSSenderImpl::new(
+		PathBuf::from("tests/test.key"),
+		"TEAMID1234".to_owned(),
+		"KEYID12345".to_owned(),
+		false,
+	)
+	.unwrap();
+
+	let debug = format!("{sender:?}");
+	assert!(!debug.contains("TEAMID1234"));
+	assert!(!debug.contains("KEYID12345"));
+}
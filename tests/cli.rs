@@ -0,0 +1,96 @@
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tests for the command line interface.
+
+use clap::Parser;
+use matrix_hedwig::{
+	cli::{Cli, Command, Service},
+	settings::{redact, Settings},
+};
+use serde_json::json;
+
+#[test]
+fn parse_defaults() -> Result<(), Box<dyn std::error::Error>> {
+	let cli = Cli::try_parse_from(["matrix-hedwig"])?;
+
+	assert_eq!(cli.config, Settings::CONFIG_FILENAME);
+	assert!(cli.command.is_none());
+
+	Ok(())
+}
+
+#[test]
+fn parse_send_test() -> Result<(), Box<dyn std::error::Error>> {
+	let cli = Cli::try_parse_from([
+		"matrix-hedwig",
+		"send-test",
+		"--config",
+		"/etc/hedwig/config.yaml",
+		"--app-id",
+		"com.famedly.🦊",
+		"--pushkey",
+		"🏰🦊🔒",
+		"--via",
+		"apns",
+	])?;
+
+	assert_eq!(cli.config, "/etc/hedwig/config.yaml");
+	let Some(Command::SendTest(args)) = cli.command else {
+		panic!("Expected the send-test subcommand, got {:?}", cli.command);
+	};
+	assert_eq!(args.app_id, "com.famedly.🦊");
+	assert_eq!(args.pushkey, "🏰🦊🔒");
+	assert!(matches!(args.via, Service::Apns));
+	assert!(args.data_message.is_none());
+
+	Ok(())
+}
+
+#[test]
+fn redact_secrets() {
+	let mut config = json!({
+		"server": {
+			"port": 7022,
+			"access": {
+				"homeservers": [
+					{ "name": "matrix.example.org", "token": "hunter2" },
+					{ "name": "other.example.org", "token": null },
+				],
+			},
+		},
+		"hedwig": { "apns_key_id": "ABCDEF1234", "client_secret": "swordfish" },
+	});
+
+	redact(&mut config);
+
+	assert_eq!(
+		config,
+		json!({
+			"server": {
+				"port": 7022,
+				"access": {
+					"homeservers": [
+						{ "name": "matrix.example.org", "token": "<redacted>" },
+						{ "name": "other.example.org", "token": null },
+					],
+				},
+			},
+			"hedwig": { "apns_key_id": "<redacted>", "client_secret": "<redacted>" },
+		})
+	);
+}
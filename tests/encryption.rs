@@ -0,0 +1,145 @@
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tests for the encryption module.
+
+#![allow(clippy::unwrap_used)]
+
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use ctr::{
+	cipher::{KeyIvInit, StreamCipher},
+	Ctr128BE,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use matrix_hedwig::{encryption, error::ErrCode, models::Notification};
+use rand_core::OsRng;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Decrypts the payload the way a client would
+fn decrypt(secret: &StaticSecret, payload: &encryption::EncryptedPayload) -> Vec<u8> {
+	let ephemeral: [u8; 32] =
+		STANDARD_NO_PAD.decode(&payload.ephemeral).unwrap().try_into().unwrap();
+	let shared_secret = secret.diffie_hellman(&PublicKey::from(ephemeral));
+
+	let mut keys = [0_u8; 80];
+	Hkdf::<Sha256>::new(Some(&[0; 32]), shared_secret.as_bytes()).expand(&[], &mut keys).unwrap();
+	let (aes_key, rest) = keys.split_at(32);
+	let (mac_key, iv) = rest.split_at(32);
+
+	let ciphertext = STANDARD_NO_PAD.decode(&payload.ciphertext).unwrap();
+	let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).unwrap();
+	mac.update(&ciphertext);
+	mac.verify_truncated_left(&STANDARD_NO_PAD.decode(&payload.mac).unwrap()).unwrap();
+
+	let mut plaintext = ciphertext;
+	Ctr128BE::<Aes256>::new_from_slices(aes_key, iv).unwrap().apply_keystream(&mut plaintext);
+	plaintext
+}
+
+#[test]
+fn roundtrip() {
+	let secret = StaticSecret::random_from_rng(OsRng);
+	let public_key = STANDARD_NO_PAD.encode(PublicKey::from(&secret).as_bytes());
+	let plaintext = br#"{"room_id":"!owo:example.org"}"#;
+
+	let payload = encryption::encrypt(&public_key, plaintext).unwrap();
+	assert_eq!(decrypt(&secret, &payload), plaintext);
+
+	// Every notification uses a new ephemeral key
+	let other = encryption::encrypt(&public_key, plaintext).unwrap();
+	assert_ne!(payload.ephemeral, other.ephemeral);
+	assert_ne!(payload.ciphertext, other.ciphertext);
+}
+
+#[test]
+fn invalid_public_key() {
+	for public_key in [
+		"",
+		"not base64!",
+		"AAAA",
+		// Low order points, giving an all-zero shared secret
+		"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+		"AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+	] {
+		let err = encryption::encrypt(public_key, b"{}").unwrap_err();
+		assert_eq!(err.errcode, ErrCode::BadJson);
+	}
+}
+
+/// Notification for a device asking for encryption with the given message
+/// body
+fn encrypted_notification(secret: &StaticSecret, body: &str) -> Value {
+	let public_key = STANDARD_NO_PAD.encode(PublicKey::from(secret).as_bytes());
+	let notification: Notification = serde_json::from_value(json!({
+		"counts": { "unread": 1 },
+		"devices": [{
+			"app_id": "com.famedly.🦊",
+			"pushkey": "fox",
+			"pushkey_ts": 1_655_896_032_i32,
+			"data": { "algorithm": encryption::ALGORITHM, "public_key": public_key },
+			"tweaks": { "sound": "bing" },
+		}],
+		"event_id": "$uwu",
+		"room_id": "!owo:example.org",
+		"type": "m.room.message",
+		"sender": "@fox:example.org",
+		"content": { "msgtype": "m.text", "body": body },
+	}))
+	.unwrap();
+
+	let encrypted = encryption::encrypt_notification(
+		&notification,
+		&notification.devices[0],
+		Some("myapp://room/!owo:example.org".to_owned()),
+	)
+	.unwrap()
+	.unwrap();
+	assert!(encrypted.room_id.is_none());
+	assert!(encrypted.event_id.is_none());
+	assert!(encrypted.r#type.is_none());
+
+	let payload = encryption::EncryptedPayload {
+		ciphertext: encrypted.ciphertext.unwrap(),
+		ephemeral: encrypted.ephemeral.unwrap(),
+		mac: encrypted.mac.unwrap(),
+	};
+	serde_json::from_slice(&decrypt(secret, &payload)).unwrap()
+}
+
+#[test]
+fn notification_plaintext() {
+	let secret = StaticSecret::random_from_rng(OsRng);
+
+	let data = encrypted_notification(&secret, "hello there");
+	assert_eq!(data["event_id"], "$uwu");
+	assert_eq!(data["room_id"], "!owo:example.org");
+	assert_eq!(data["type"], "m.room.message");
+	assert_eq!(data["link"], "myapp://room/!owo:example.org");
+	assert!(data["content"].as_str().unwrap().contains("hello there"));
+	// The device list with the tweaks and pushkey is of no use to the client
+	assert!(data.get("devices").is_none());
+
+	// Long messages are fetched by the client instead of exceeding the push
+	// service size limits
+	let data = encrypted_notification(&secret, &"🦊".repeat(1000));
+	assert!(data.get("content").is_none());
+	assert_eq!(data["event_id"], "$uwu");
+}
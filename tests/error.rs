@@ -0,0 +1,74 @@
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tests for the error module.
+
+#![allow(clippy::unwrap_used)]
+
+use axum::{http::StatusCode, response::IntoResponse};
+use firebae_cm::FcmError;
+use matrix_hedwig::error::{ErrCode, HedwigError};
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn server_error_response() {
+	let response = HedwigError {
+		error: "FCM is having a bad day".to_owned(),
+		errcode: ErrCode::ProviderUnavailable,
+	}
+	.into_response();
+	assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+	let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+	let body: Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(
+		body,
+		json!({
+			"errcode": "M_UNKNOWN",
+			"error": "FCM is having a bad day",
+			"com.famedly.hedwig.errcode": "PROVIDER_UNAVAILABLE",
+		})
+	);
+}
+
+#[test]
+fn fcm_errors_reject_pushkey() {
+	for (status, rejects_pushkey) in [
+		("UNREGISTERED", true),
+		("NOT_FOUND", true),
+		("UNAVAILABLE", false),
+		("INTERNAL", false),
+		("QUOTA_EXCEEDED", false),
+		("UNAUTHENTICATED", false),
+		("INVALID_ARGUMENT", false),
+	] {
+		let error = HedwigError::from(firebae_cm::Error::FcmError(FcmError {
+			code: 0,
+			status: status.to_owned(),
+			message: "blubb".to_owned(),
+		}));
+		assert_eq!(error.errcode.rejects_pushkey(), rejects_pushkey, "{status}");
+		assert_eq!(error.errcode.status_code().is_server_error(), !rejects_pushkey, "{status}");
+	}
+}
+
+#[test]
+fn serialization_errors_keep_pushkey() {
+	let error = HedwigError::from(serde_json::from_str::<Value>("{").unwrap_err());
+	assert!(!error.errcode.rejects_pushkey());
+	assert!(error.errcode.status_code().is_server_error());
+}
@@ -21,7 +21,11 @@
 
 use std::path::PathBuf;
 
-use matrix_hedwig::{error::HedwigError, fcm::FcmSenderImpl};
+use firebae_cm::FcmError;
+use matrix_hedwig::{
+	error::{ErrCode, HedwigError},
+	fcm::FcmSenderImpl,
+};
 
 #[tokio::test]
 async fn fcm_sender_create() {
@@ -44,3 +48,23 @@ fn fcm_error() {
 		r#"HedwigError { error: "Failed to authenticate with fcm!", errcode: FcmAuthFailed }"#
 	);
 }
+
+#[test]
+fn fcm_error_reason() {
+	for (status, errcode, reason) in [
+		("NOT_FOUND", ErrCode::Unregistered, "Unregistered"),
+		("RESOURCE_EXHAUSTED", ErrCode::QuotaExceeded, "QuotaExceeded"),
+		("UNAUTHENTICATED", ErrCode::FcmAuthFailed, "AuthError"),
+		("UNAVAILABLE", ErrCode::ProviderUnavailable, "Unavailable"),
+		("Bad Request", ErrCode::FcmFailed, "Other"),
+	] {
+		let error: HedwigError = firebae_cm::Error::FcmError(FcmError {
+			code: 0,
+			status: status.to_owned(),
+			message: "blubb".to_owned(),
+		})
+		.into();
+		assert_eq!(error.errcode, errcode);
+		assert_eq!(error.errcode.reason(), reason);
+	}
+}
@@ -20,17 +20,21 @@
 #![allow(clippy::unwrap_used)]
 #![allow(clippy::expect_used)]
 
-use std::{path::PathBuf, sync::Arc};
-
-use a2::{
-	request::payload::{Payload, PayloadLike},
-	PushType,
+use std::{
+	collections::BTreeMap,
+	net::SocketAddr,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
+
+use a2::{request::payload::PayloadLike, PushType};
 use async_trait::async_trait;
 use axum::{
 	body::Body,
+	extract::ConnectInfo,
 	http::{
-		header::{CONTENT_LENGTH, CONTENT_TYPE},
+		header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
 		StatusCode,
 	},
 	Router,
@@ -38,11 +42,16 @@ use axum::{
 use color_eyre::Report;
 use firebae_cm::{FcmError, MessageBody};
 use matrix_hedwig::{
+	admin::create_admin_router,
 	api::{create_router, AppState},
-	apns::APNSSender,
+	apns::{APNSSender, ApnsNotification, ApnsResponse},
 	error::{ErrCode, HedwigError},
 	fcm::FcmSender,
-	models::{ApnsHeaders, ApnsPayload, Metrics, NotificationMethod},
+	models::{
+		hash_pushkey, ApnsHeaders, ApnsPayload, InterruptionLevel, Metrics, Notification,
+		NotificationMethod,
+	},
+	reload::Snapshot,
 	settings::{self, DeserializablePushType, Settings},
 };
 use opentelemetry::{metrics::MeterProvider, KeyValue};
@@ -58,13 +67,20 @@ struct FakeFcmSender(mpsc::Sender<MessageBody>);
 #[async_trait]
 impl FcmSender for FakeFcmSender {
 	async fn send(&self, message: MessageBody) -> Result<String, HedwigError> {
-		let should_fail = format!("{message:?}").contains("fcm_fail_pls");
+		let message_debug = format!("{message:?}");
+		let status = [
+			("fcm_unregistered_pls", "UNREGISTERED"),
+			("fcm_unavailable_pls", "UNAVAILABLE"),
+			("fcm_fail_pls", "Bad Request"),
+		]
+		.into_iter()
+		.find_map(|(trigger, status)| message_debug.contains(trigger).then_some(status));
 
 		self.0.send(message).await.unwrap();
-		if should_fail {
+		if let Some(status) = status {
 			Err(firebae_cm::Error::FcmError(FcmError {
 				code: 0,
-				status: "Bad Request".to_owned(),
+				status: status.to_owned(),
 				message: "blubb".to_owned(),
 			})
 			.into())
@@ -76,84 +92,119 @@ impl FcmSender for FakeFcmSender {
 
 #[derive(Debug)]
 struct FakeAPNSSender {
-	tx: mpsc::Sender<Payload>,
+	tx: mpsc::Sender<ApnsNotification>,
 }
 #[async_trait]
 impl APNSSender for FakeAPNSSender {
-	async fn send(&self, payload: Payload) -> Result<(), HedwigError> {
+	async fn send(&self, payload: ApnsNotification) -> Result<ApnsResponse, HedwigError> {
 		let should_fail = payload.device_token.contains("apns_fail_pls");
 
 		self.tx.send(payload).await.unwrap();
 		if should_fail {
 			Err(HedwigError { error: "Bad Request".to_owned(), errcode: ErrCode::APNSFailed })
 		} else {
-			Ok(())
+			Ok(ApnsResponse { apns_id: Some("🦊".to_owned()), code: 200 })
 		}
 	}
 }
 
+fn test_settings() -> Settings {
+	let log = settings::Log { level: "DEBUG".to_owned(), privacy: settings::Privacy::Full };
+
+	let server = settings::Server {
+		port: 4567,
+		bind_address: [0, 0, 0, 0].into(),
+		access: settings::Access::default(),
+		tls: None,
+		shutdown_grace_period: Settings::DEFAULT_SHUTDOWN_GRACE_PERIOD,
+		shutdown_delay: Settings::DEFAULT_SHUTDOWN_DELAY,
+		readiness_check_interval: Settings::DEFAULT_READINESS_CHECK_INTERVAL,
+		admin: None,
+	};
+
+	let hedwig = settings::Hedwig {
+		app_id: "com.famedly.🦊".to_owned(),
+		push_max_retries: 4,
+		notification_title: "🦊 <count> 🦊".to_owned(),
+		notification_body: "read the notification pls :c".to_owned(),
+		notification_sound: "default".to_owned(),
+		notification_android: settings::FcmNotificationAndroid {
+			icon: "notifications_icon".to_owned(),
+			tag: "org.matrix.default_notification".to_owned(),
+			channel_id: "org.matrix.app.message".to_owned(),
+			color: None,
+			body_loc_key: None,
+			body_loc_args: None,
+			title_loc_key: None,
+			title_loc_args: None,
+			ticker: None,
+			sticky: None,
+			event_time: None,
+			local_only: None,
+			default_sound: None,
+			notification_priority: None,
+			default_vibrate_timings: None,
+			default_light_settings: None,
+			vibrate_timings: None,
+			visibility: None,
+			light_settings: None,
+			image: None,
+		},
+		notification_click_action: "FLUTTER_NOTIFICATION_CLICK".to_owned(),
+		notification_link: None,
+		notification_image: None,
+		provider_privacy: settings::ProviderPrivacy::Full,
+		apns_headers: ApnsHeaders {
+			apns_push_type: DeserializablePushType(PushType::Background),
+			apns_topic: Some("app.bundle.id".to_owned()),
+			apns_collapse_id: None,
+			apns_expiration: None,
+			apns_id: None,
+			apns_priority: Some("5".into()),
+		},
+		apns_payload: ApnsPayload {
+			category: None,
+			content_available: None,
+			mutable_content: Some(1),
+			interruption_level: None,
+			highlight_interruption_level: None,
+			relevance_score: None,
+			thread_id: None,
+			subtitle: None,
+			target_content_id: None,
+			filter_criteria: None,
+			custom: BTreeMap::new(),
+		},
+		notification_request_body_size_limit:
+			Settings::DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT,
+		legacy_data_message: settings::LegacyDataMessage::Warn,
+		badge_only_data_message: false,
+		badge_includes_missed_calls: false,
+		call_invite: None,
+		missed_call: None,
+		notification_android_overrides: vec![],
+		webpush: settings::Webpush::default(),
+		apns_key_file_path: None,
+		fcm_credentials_file_path: PathBuf::from(""),
+		apns_key_id: "".to_owned(),
+		apns_team_id: "".to_owned(),
+		apns_sandbox: false,
+	};
+	Settings { log, server, hedwig, telemetry: OtelConfig::default(), redacted: Value::Null }
+}
+
 fn setup_server(
 	fcm_sender: Box<dyn FcmSender + Send + Sync>,
 	apns_sender: Option<Box<dyn APNSSender + Send + Sync>>,
 ) -> Result<Router, Report> {
-	let settings = {
-		let log = settings::Log { level: "DEBUG".to_owned() };
-
-		let server = settings::Server { port: 4567, bind_address: [0, 0, 0, 0].into() };
-
-		let hedwig = settings::Hedwig {
-			app_id: "com.famedly.🦊".to_owned(),
-			push_max_retries: 4,
-			notification_title: "🦊 <count> 🦊".to_owned(),
-			notification_body: "read the notification pls :c".to_owned(),
-			notification_sound: "default".to_owned(),
-			notification_android: settings::FcmNotificationAndroid {
-				icon: "notifications_icon".to_owned(),
-				tag: "org.matrix.default_notification".to_owned(),
-				channel_id: "org.matrix.app.message".to_owned(),
-				color: None,
-				body_loc_key: None,
-				body_loc_args: None,
-				title_loc_key: None,
-				title_loc_args: None,
-				ticker: None,
-				sticky: None,
-				event_time: None,
-				local_only: None,
-				default_sound: None,
-				notification_priority: None,
-				default_vibrate_timings: None,
-				default_light_settings: None,
-				vibrate_timings: None,
-				visibility: None,
-				light_settings: None,
-				image: None,
-			},
-			notification_click_action: "FLUTTER_NOTIFICATION_CLICK".to_owned(),
-			apns_headers: ApnsHeaders {
-				apns_push_type: DeserializablePushType(PushType::Background),
-				apns_topic: Some("app.bundle.id".to_owned()),
-				apns_collapse_id: None,
-				apns_expiration: None,
-				apns_id: None,
-				apns_priority: Some("5".into()),
-			},
-			apns_payload: ApnsPayload {
-				category: None,
-				content_available: None,
-				mutable_content: Some(1),
-			},
-			notification_request_body_size_limit:
-				Settings::DEFAULT_NOTIFICATION_REQUEST_BODY_SIZE_LIMIT,
-			apns_key_file_path: None,
-			fcm_credentials_file_path: PathBuf::from(""),
-			apns_key_id: "".to_owned(),
-			apns_team_id: "".to_owned(),
-			apns_sandbox: false,
-		};
-		Settings { log, server, hedwig, telemetry: OtelConfig::default() }
-	};
+	setup_server_with_settings(test_settings(), fcm_sender, apns_sender)
+}
 
+fn setup_server_with_settings(
+	settings: Settings,
+	fcm_sender: Box<dyn FcmSender + Send + Sync>,
+	apns_sender: Option<Box<dyn APNSSender + Send + Sync>>,
+) -> Result<Router, Report> {
 	let registry = prometheus::Registry::new();
 	let exporter = opentelemetry_prometheus::exporter().with_registry(registry.clone()).build()?;
 
@@ -297,6 +348,11 @@ async fn check_prom(
 	let re = Regex::new(r"} [0-9]\.[0-9]+")?;
 	let data = re.replace_all(&data, "} FLOAT");
 
+	// Which latency bucket a push lands in depends on the machine running the
+	// tests, only the boundaries and the total count are stable
+	let re = Regex::new(r#"(push_duration_seconds_bucket\{[^}]*le="[0-9.]+"\}) [0-9]+"#)?;
+	let data = re.replace_all(&data, "$1 COUNT");
+
 	// any version of the telemetry sdk is fine. we do this to avoid test failure on
 	// simple version bumping
 	let processed_data = Regex::new(r#"telemetry_sdk_version="[^"]*""#)
@@ -326,7 +382,11 @@ async fn fcm_failure() -> Result<(), Box<dyn std::error::Error>> {
 			"prio": "high"
 		}
 	});
-	assert_eq!("{\"rejected\":[\"Android\"]}", run_request(&mut service, msg).await?);
+	// A push service failure is no reason to remove the pusher
+	assert_eq!(
+		"{\"errcode\":\"M_UNKNOWN\",\"error\":\"FCM responded with Bad Request (0): blubb\",\"com.famedly.hedwig.errcode\":\"FCM_FAILED\"}",
+		run_request(&mut service, msg).await?
+	);
 
 	Ok(())
 }
@@ -404,7 +464,7 @@ async fn bad_json() -> Result<(), Box<dyn std::error::Error>> {
 
 	assert_eq!(
 		&data,
-		"{\"error\":\"Failed to parse the request body as JSON: expected value at line 1 column 1\",\"errcode\":\"BAD_JSON\"}"
+		"{\"errcode\":\"M_NOT_JSON\",\"error\":\"Failed to parse the request body as JSON: expected value at line 1 column 1\",\"com.famedly.hedwig.errcode\":\"NOT_JSON\"}"
 	);
 	Ok(())
 }
@@ -435,10 +495,13 @@ async fn push_body_limit() -> Result<(), Box<dyn std::error::Error>> {
 		)
 		.await?;
 
-	assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+	assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
 
 	let data = response_to_string(resp).await?;
-	assert_eq!(&data, "{\"error\":\"Failed to buffer the request body: length limit exceeded\",\"errcode\":\"BAD_JSON\"}");
+	assert_eq!(
+		&data,
+		"{\"errcode\":\"M_TOO_LARGE\",\"error\":\"Failed to buffer the request body: length limit exceeded\",\"com.famedly.hedwig.errcode\":\"TOO_LARGE\"}"
+	);
 
 	Ok(())
 }
@@ -590,7 +653,7 @@ impl FcmSender for PanickingFcmSender {
 struct PanickingAPNSSender {}
 #[async_trait]
 impl APNSSender for PanickingAPNSSender {
-	async fn send(&self, _payload: Payload) -> Result<(), HedwigError> {
+	async fn send(&self, _payload: ApnsNotification) -> Result<ApnsResponse, HedwigError> {
 		panic!("Run for your lives!");
 	}
 }
@@ -619,6 +682,1197 @@ async fn panic_handler() -> Result<(), Box<dyn std::error::Error>> {
 		StatusCode::INTERNAL_SERVER_ERROR,
 		"Got incorrect error code: {response_string}"
 	);
+	let error: Value = serde_json::from_str(&response_string)?;
+	assert_eq!(error["errcode"], "M_UNKNOWN");
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn access_control_token() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.server.access.homeservers = vec![settings::HomeserverAccess {
+		name: "matrix.example.org".to_owned(),
+		token: Some("hunter2".to_owned()),
+		client_certificate_fingerprints: Vec::new(),
+		trusted: false,
+	}];
+	let mut service = setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let body = serde_json::to_string(&test_message(
+		false,
+		vec![get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm)],
+	))?;
+
+	for (authorization, expected_status) in [
+		(None, StatusCode::UNAUTHORIZED),
+		(Some("Bearer hunter3"), StatusCode::FORBIDDEN),
+		(Some("Bearer hunter2"), StatusCode::OK),
+	] {
+		let mut request = axum::http::Request::post("/_matrix/push/v1/notify")
+			.header(CONTENT_TYPE, "application/json")
+			.header(CONTENT_LENGTH, body.len());
+		if let Some(authorization) = authorization {
+			request = request.header(AUTHORIZATION, authorization);
+		}
+
+		let resp = service.call(request.body(Body::from(body.clone()))?).await?;
+		assert_eq!(resp.status(), expected_status);
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn access_control_network() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.server.access.allowed_networks = vec!["10.0.0.0/8".parse()?];
+	let mut service = setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let body = serde_json::to_string(&test_message(
+		false,
+		vec![get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm)],
+	))?;
+
+	for (peer, expected_status) in [
+		(None, StatusCode::FORBIDDEN),
+		(Some("192.168.0.1:1234"), StatusCode::FORBIDDEN),
+		(Some("10.13.37.1:1234"), StatusCode::OK),
+	] {
+		let mut request = axum::http::Request::post("/_matrix/push/v1/notify")
+			.header(CONTENT_TYPE, "application/json")
+			.header(CONTENT_LENGTH, body.len());
+		if let Some(peer) = peer {
+			request = request.extension(ConnectInfo(peer.parse::<SocketAddr>()?));
+		}
+
+		let resp = service.call(request.body(Body::from(body.clone()))?).await?;
+		assert_eq!(resp.status(), expected_status);
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn readiness_during_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let metrics = Metrics::new(&opentelemetry::global::meter("Hedwig"));
+	let app_state = AppState::new(Box::new(FakeFcmSender(fcm_tx)), None, test_settings(), metrics);
+	let shutdown = app_state.shutdown().clone();
+	app_state.run_checks().await;
+	let mut service = create_router(app_state, Arc::new(prometheus::Registry::new()))?;
+
+	let resp = service.call(axum::http::Request::get("/ready").body(Body::empty())?).await?;
+	assert_eq!(resp.status(), StatusCode::OK);
+	let readiness: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(readiness["ready"], json!(true));
+	assert_eq!(readiness["shutting_down"], json!(false));
+	assert_eq!(readiness["components"]["fcm"]["healthy"], json!(true));
+	assert!(readiness["components"]["fcm"]["last_checked"].is_string());
+	assert!(readiness["components"].get("apns").is_none());
+
+	shutdown.trigger();
+
+	let resp = service.call(axum::http::Request::get("/ready").body(Body::empty())?).await?;
+	assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+	let readiness: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(readiness["ready"], json!(false));
+	assert_eq!(readiness["shutting_down"], json!(true));
+
+	let resp = service.call(axum::http::Request::get("/health").body(Body::empty())?).await?;
+	assert_eq!(resp.status(), StatusCode::OK);
+
+	// The listener keeps accepting connections until the shutdown delay passed
+	let delay = Duration::from_secs(60);
+	assert!(tokio::time::timeout(Duration::from_millis(100), shutdown.draining(delay))
+		.await
+		.is_err());
+	assert!(tokio::time::timeout(Duration::from_secs(1), shutdown.draining(Duration::ZERO))
+		.await
+		.is_ok());
+
+	Ok(())
+}
+
+#[derive(Debug)]
+struct ExpiredAPNSSender;
+#[async_trait]
+impl APNSSender for ExpiredAPNSSender {
+	async fn send(&self, _payload: ApnsNotification) -> Result<ApnsResponse, HedwigError> {
+		Ok(ApnsResponse { apns_id: None, code: 200 })
+	}
+
+	async fn check(&self) -> Result<(), HedwigError> {
+		Err(HedwigError { error: "Key revoked".to_owned(), errcode: ErrCode::APNSAuthFailed })
+	}
+}
+
+#[tokio::test]
+async fn readiness_failed_check() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let metrics = Metrics::new(&opentelemetry::global::meter("Hedwig"));
+	let app_state = AppState::new(
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(ExpiredAPNSSender)),
+		test_settings(),
+		metrics,
+	);
+	app_state.run_checks().await;
+	let mut service = create_router(app_state.clone(), Arc::new(prometheus::Registry::new()))?;
+
+	let resp = service.call(axum::http::Request::get("/ready").body(Body::empty())?).await?;
+	assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+	let readiness: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(readiness["ready"], json!(false));
+	assert_eq!(readiness["components"]["fcm"]["healthy"], json!(true));
+	assert_eq!(readiness["components"]["apns"]["healthy"], json!(false));
+	assert!(readiness["components"]["apns"]["error"].as_str().unwrap().contains("Key revoked"));
+
+	// Dropping APNs from the config also drops its failed check
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	app_state.config().replace(Snapshot::new(
+		Box::new(FakeFcmSender(fcm_tx)),
+		None,
+		test_settings(),
+	));
+	app_state.run_checks().await;
+
+	let resp = service.call(axum::http::Request::get("/ready").body(Body::empty())?).await?;
+	assert_eq!(resp.status(), StatusCode::OK);
+	let readiness: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(readiness["ready"], json!(true));
+	assert!(readiness["components"].get("apns").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn config_swap() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let metrics = Metrics::new(&opentelemetry::global::meter("Hedwig"));
+	let app_state =
+		AppState::new(Box::new(FakeFcmSender(fcm_tx.clone())), None, test_settings(), metrics);
+	let config = app_state.config().clone();
+	let mut service = create_router(app_state, Arc::new(prometheus::Registry::new()))?;
+
+	let message = test_message(
+		false,
+		vec![get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm)],
+	);
+
+	run_request(&mut service, message.clone()).await?;
+	let posted_message = serde_json::to_string(&fcm_rx.recv().await.unwrap())?;
+	assert!(posted_message.contains("read the notification pls :c"));
+
+	let mut settings = test_settings();
+	settings.hedwig.notification_body = "new config, who dis".to_owned();
+	config.replace(Snapshot::new(Box::new(FakeFcmSender(fcm_tx)), None, settings));
+
+	run_request(&mut service, message).await?;
+	let posted_message = serde_json::to_string(&fcm_rx.recv().await.unwrap())?;
+	assert!(posted_message.contains("new config, who dis"));
+
+	Ok(())
+}
+
+async fn admin_request(
+	service: &mut Router,
+	request: axum::http::request::Builder,
+	body: Body,
+) -> Result<(StatusCode, Value), Box<dyn std::error::Error>> {
+	let resp = service.call(request.header(AUTHORIZATION, "Bearer sesame").body(body)?).await?;
+	let status = resp.status();
+
+	Ok((status, serde_json::from_str(&response_to_string(resp).await?)?))
+}
+
+#[tokio::test]
+async fn admin_api() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let admin = settings::Admin {
+		port: 4568,
+		bind_address: [127, 0, 0, 1].into(),
+		token: "sesame".to_owned(),
+	};
+	let mut settings = test_settings();
+	settings.server.admin = Some(admin.clone());
+	let metrics = Metrics::new(&opentelemetry::global::meter("Hedwig"));
+	let app_state = AppState::new(
+		Box::new(FakeFcmSender(fcm_tx.clone())),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+		settings,
+		metrics,
+	);
+	let mut service = create_admin_router(app_state.clone());
+
+	for (authorization, status, errcode) in [
+		(None, StatusCode::UNAUTHORIZED, "M_MISSING_TOKEN"),
+		(Some("Bearer open sesame"), StatusCode::FORBIDDEN, "M_UNKNOWN_TOKEN"),
+		(Some("Bearer "), StatusCode::FORBIDDEN, "M_UNKNOWN_TOKEN"),
+	] {
+		let mut request = axum::http::Request::get("/admin/senders");
+		if let Some(authorization) = authorization {
+			request = request.header(AUTHORIZATION, authorization);
+		}
+		let resp = service.call(request.body(Body::empty())?).await?;
+		assert_eq!(resp.status(), status);
+		let error: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+		assert_eq!(error["errcode"], errcode);
+	}
+
+	let (status, senders) =
+		admin_request(&mut service, axum::http::Request::get("/admin/senders"), Body::empty())
+			.await?;
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(senders["fcm"]["healthy"], json!(true));
+	assert_eq!(senders["apns"]["healthy"], json!(true));
+
+	for (platform, notify_via, expected) in [
+		(Platform::Android, NotificationMethod::Fcm, json!({"provider": "fcm", "name": "owo"})),
+		(
+			Platform::IoS,
+			NotificationMethod::Apns,
+			json!({"provider": "apns", "apns_id": "🦊", "code": 200}),
+		),
+	] {
+		let device = get_device("com.famedly.🦊", platform, notify_via.clone());
+		let (status, response) = admin_request(
+			&mut service,
+			axum::http::Request::post("/admin/test-push").header(CONTENT_TYPE, "application/json"),
+			Body::from(serde_json::to_string(&device)?),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(response, expected);
+
+		match notify_via {
+			NotificationMethod::Apns => assert!(apns_rx.recv().await.is_some()),
+			NotificationMethod::Fcm => assert!(fcm_rx.recv().await.is_some()),
+		}
+	}
+
+	let mut device = get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm);
+	device["pushkey"] = json!("fcm_fail_pls");
+	let (status, error) = admin_request(
+		&mut service,
+		axum::http::Request::post("/admin/test-push").header(CONTENT_TYPE, "application/json"),
+		Body::from(serde_json::to_string(&device)?),
+	)
+	.await?;
+	assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+	assert_eq!(error["error"], json!("FCM responded with Bad Request (0): blubb"));
+	assert!(fcm_rx.recv().await.is_some());
+
+	let (status, error) =
+		admin_request(&mut service, axum::http::Request::get("/admin/config"), Body::empty())
+			.await?;
+	assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+	assert_eq!(error["errcode"], json!("M_UNKNOWN"));
+	assert_eq!(error["com.famedly.hedwig.errcode"], json!("CONFIG_FAILED"));
+
+	let mut settings = Settings::load("config.sample.yaml")?;
+	settings.server.admin = Some(admin);
+	app_state.config().replace(Snapshot::new(Box::new(FakeFcmSender(fcm_tx)), None, settings));
+	let (status, config) =
+		admin_request(&mut service, axum::http::Request::get("/admin/config"), Body::empty())
+			.await?;
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(config["hedwig"]["app_id"], json!("org.matrix.awesome_client"));
+	assert_eq!(config["hedwig"]["apns_team_id"], json!("<redacted>"));
+	assert_eq!(config["hedwig"]["apns_key_id"], json!("<redacted>"));
+
+	Ok(())
+}
+
+#[test]
+fn notification_debug_redacted() -> Result<(), Box<dyn std::error::Error>> {
+	let mut message = test_message(
+		false,
+		vec![get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm)],
+	);
+	message["notification"]["devices"][0]["pushkey"] = json!("🏰🦊🔒");
+	message["notification"]["ciphertext"] = json!("s3cr3t c1ph3rt3xt");
+	message["notification"]["content"] = json!({"body": "top secret message"});
+	let notification: Notification = serde_json::from_value(message["notification"].clone())?;
+
+	let debug = format!("{notification:?}");
+
+	assert!(!debug.contains("🏰🦊🔒"));
+	assert!(debug.contains(&hash_pushkey("🏰🦊🔒")));
+	assert!(!debug.contains("s3cr3t c1ph3rt3xt"));
+	assert!(!debug.contains("top secret message"));
+	assert!(debug.contains("room_id: Some(\"owo\")"));
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn delivery_outcomes() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, _apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.push_max_retries = 0;
+	settings.server.access.homeservers = vec![
+		settings::HomeserverAccess {
+			name: "trusted.example.org".to_owned(),
+			token: Some("trusted".to_owned()),
+			client_certificate_fingerprints: Vec::new(),
+			trusted: true,
+		},
+		settings::HomeserverAccess {
+			name: "matrix.example.org".to_owned(),
+			token: Some("hunter2".to_owned()),
+			client_certificate_fingerprints: Vec::new(),
+			trusted: false,
+		},
+	];
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let body = serde_json::to_string(&json!({
+		"notification": {
+			"devices": [
+				get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm),
+				get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns),
+			],
+			"room_id": "fcm_fail_pls",
+		}
+	}))?;
+
+	for (token, trusted) in [("trusted", true), ("hunter2", false)] {
+		let resp = service
+			.call(
+				axum::http::Request::post("/_matrix/push/v1/notify")
+					.header(CONTENT_TYPE, "application/json")
+					.header(CONTENT_LENGTH, body.len())
+					.header(AUTHORIZATION, format!("Bearer {token}"))
+					.body(Body::from(body.clone()))?,
+			)
+			.await?;
+		let mut resp: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+
+		assert_eq!(resp["rejected"], json!([]));
+		if !trusted {
+			assert!(resp.get("com.famedly.hedwig.outcomes").is_none());
+			continue;
+		}
+
+		let outcomes = resp["com.famedly.hedwig.outcomes"].as_array_mut().unwrap();
+		for outcome in outcomes.iter_mut() {
+			assert!(outcome["latency_ms"].is_u64());
+			outcome.as_object_mut().unwrap().remove("latency_ms");
+		}
+		assert_eq!(
+			*outcomes,
+			[
+				json!({
+					"pushkey": "Android",
+					"transport": "fcm",
+					"attempts": 1,
+					"status": "failed",
+					"errcode": "FCM_FAILED",
+					"error": "FCM responded with Bad Request (0): blubb",
+				}),
+				json!({
+					"pushkey": "IoS",
+					"transport": "apns",
+					"attempts": 1,
+					"status": "delivered",
+					"message_id": "🦊",
+				}),
+			]
+		);
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn provider_failures_keep_pushkeys() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, _apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.push_max_retries = 0;
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let device = |pushkey: &str| {
+		let mut device = get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm);
+		device["pushkey"] = json!(pushkey);
+		device
+	};
+
+	// Only the pushkey FCM doesn't know anymore is rejected
+	let devices = vec![
+		device("fcm_unavailable_pls"),
+		device("fcm_unregistered_pls"),
+		get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns),
+	];
+	let resp = run_request(&mut service, test_message(false, devices)).await?;
+	assert_eq!(&resp, "{\"rejected\":[\"fcm_unregistered_pls\"]}");
+
+	// If no device could be pushed to, the homeserver has to retry later
+	let body = serde_json::to_string(&test_message(false, vec![device("fcm_unavailable_pls")]))?;
+	let resp = service
+		.call(
+			axum::http::Request::post("/_matrix/push/v1/notify")
+				.header(CONTENT_TYPE, "application/json")
+				.header(CONTENT_LENGTH, body.len())
+				.body(Body::from(body))?,
+		)
+		.await?;
+	assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+	let error: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(error["errcode"], "M_UNKNOWN");
+	assert_eq!(error["com.famedly.hedwig.errcode"], "PROVIDER_UNAVAILABLE");
+	assert!(error.get("rejected").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn config_failures_keep_pushkeys() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, _apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.push_max_retries = 0;
+	// APNs only accepts collapse ids of up to 64 bytes
+	settings.hedwig.apns_headers.apns_collapse_id = Some("🦊".repeat(17));
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let apns_device = get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns);
+	let devices = vec![
+		get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm),
+		apns_device.clone(),
+	];
+	let resp = run_request(&mut service, test_message(false, devices)).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let body = serde_json::to_string(&test_message(false, vec![apns_device]))?;
+	let resp = service
+		.call(
+			axum::http::Request::post("/_matrix/push/v1/notify")
+				.header(CONTENT_TYPE, "application/json")
+				.header(CONTENT_LENGTH, body.len())
+				.body(Body::from(body))?,
+		)
+		.await?;
+	assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+	let error: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+	assert_eq!(error["com.famedly.hedwig.errcode"], "CONFIG_FAILED");
+	assert!(error.get("rejected").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn legacy_data_message_rejected() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, _apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.legacy_data_message = settings::LegacyDataMessage::Reject;
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let devices = vec![
+		get_device("com.famedly.🦊", Platform::AndroidLegacy, NotificationMethod::Fcm),
+		get_device("com.famedly.🦊", Platform::Android, NotificationMethod::Fcm),
+	];
+	let resp = run_request(&mut service, test_message(false, devices)).await?;
+	assert_eq!(&resp, "{\"rejected\":[\"AndroidLegacy\"]}");
+
+	// Only the device using the data field got pushed to
+	let posted_message = serde_json::to_string(&fcm_rx.recv().await.unwrap())?;
+	assert_eq!(posted_message, std::fs::read_to_string("tests/message_android.json")?.trim_end());
+	assert!(fcm_rx.try_recv().is_err());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn notification_format() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let mut service = setup_server(Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let mut full_device = get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Fcm);
+	full_device["data"] = json!({ "data_message": "ios" });
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1337_i32 },
+			"devices": [
+				get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Fcm),
+				full_device,
+			],
+			"event_id": "$uwu",
+			"room_id": "owo",
+			"sender": "@fox:example.org",
+			"sender_display_name": "Fox",
+			"content": { "msgtype": "m.text", "body": "hello there" },
+			"prio": "high"
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	// event_id_only gets neither content nor metadata
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert!(message["data"].get("content").is_none());
+	assert!(message["data"].get("devices").is_none());
+	assert_eq!(message["data"]["event_id"], "$uwu");
+	assert!(message["data"].get("sender").is_none());
+	assert!(message["data"].get("sender_display_name").is_none());
+	assert_eq!(message["notification"]["title"], "🦊 1337 🦊");
+	assert_eq!(message["notification"]["body"], "read the notification pls :c");
+
+	// The full format gets everything, and the event in the alert
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["data"]["content"], "{\"body\":\"hello there\",\"msgtype\":\"m.text\"}");
+	assert_eq!(message["data"]["sender"], "@fox:example.org");
+	assert_eq!(message["data"]["sender_display_name"], "Fox");
+	assert_eq!(message["notification"]["title"], "Fox");
+	assert_eq!(message["notification"]["body"], "hello there");
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn badge_only_room_read() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.badge_only_data_message = true;
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 0 },
+			"devices": [get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns)],
+			"room_id": "owo",
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let payload = apns_rx.recv().await.unwrap();
+	let posted: Value = serde_json::from_str(&payload.to_json_string()?)?;
+	assert_eq!(posted["aps"], json!({ "badge": 0, "content-available": 1 }));
+	assert_eq!(posted["room_id"], "owo");
+	assert_eq!(posted["counts"], "{\"unread\":0,\"missed_calls\":null}");
+	assert!(matches!(payload.options.apns_push_type, Some(PushType::Background)));
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn call_notifications() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.badge_includes_missed_calls = true;
+	settings.hedwig.call_invite = Some(settings::CallNotification {
+		title: "Incoming call".to_owned(),
+		body: "Pick up!".to_owned(),
+		channel_id: Some("org.matrix.app.call".to_owned()),
+		category: Some("CALL_INVITE".to_owned()),
+	});
+	settings.hedwig.missed_call = Some(settings::CallNotification {
+		title: "<missed_calls> missed calls".to_owned(),
+		body: "<count> unread".to_owned(),
+		channel_id: None,
+		category: None,
+	});
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	for (r#type, title, body, channel_id, category) in [
+		("m.call.invite", "Incoming call", "Pick up!", "org.matrix.app.call", json!("CALL_INVITE")),
+		("m.call.hangup", "1 missed calls", "2 unread", "org.matrix.app.message", Value::Null),
+		(
+			"m.room.message",
+			"🦊 2 🦊",
+			"read the notification pls :c",
+			"org.matrix.app.message",
+			Value::Null,
+		),
+	] {
+		let request = json!({
+			"notification": {
+				"counts": { "unread": 2, "missed_calls": 1 },
+				"devices": [
+					get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm),
+					get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns),
+				],
+				"room_id": "owo",
+				"type": r#type,
+			}
+		});
+		let resp = run_request(&mut service, request).await?;
+		assert_eq!(&resp, "{\"rejected\":[]}");
+
+		let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+		assert_eq!(message["notification"], json!({ "title": title, "body": body }));
+		assert_eq!(message["android"]["notification"]["channel_id"], channel_id);
+		assert_eq!(message["apns"]["payload"]["aps"]["badge"], 3);
+		assert_eq!(message["apns"]["payload"]["aps"]["category"], category);
+
+		let payload: Value =
+			serde_json::from_str(&apns_rx.recv().await.unwrap().to_json_string()?)?;
+		assert_eq!(payload["aps"]["alert"], json!({ "title": title, "body": body }));
+		assert_eq!(payload["aps"]["badge"], 3);
+		assert_eq!(payload["aps"]["category"], category);
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn apns_payload_fields() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.apns_payload = ApnsPayload {
+		category: Some("MESSAGE".to_owned()),
+		content_available: None,
+		mutable_content: Some(1),
+		interruption_level: Some(InterruptionLevel::Active),
+		highlight_interruption_level: Some(InterruptionLevel::TimeSensitive),
+		relevance_score: Some(0.5),
+		thread_id: Some("<room_id>".to_owned()),
+		subtitle: Some("<missed_calls> missed calls".to_owned()),
+		target_content_id: Some("room-<room_id>".to_owned()),
+		filter_criteria: Some("work".to_owned()),
+		custom: BTreeMap::from([("hedwig".to_owned(), json!({ "version": 1 }))]),
+	};
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let mut fcm_device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	fcm_device["tweaks"] = json!({ "highlight": true });
+	let apns_device = get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns);
+	let request = json!({
+		"notification": {
+			"counts": { "unread": 2, "missed_calls": 1 },
+			"devices": [fcm_device, apns_device],
+			"room_id": "owo",
+		}
+	});
+	let resp = run_request(&mut service, request).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let expected = json!({
+		"aps": {
+			"alert": {
+				"title": "🦊 2 🦊",
+				"subtitle": "1 missed calls",
+				"body": "read the notification pls :c",
+			},
+			"badge": 2,
+			"sound": "default",
+			"thread-id": "owo",
+			"category": "MESSAGE",
+			"mutable-content": 1,
+			"target-content-id": "room-owo",
+			"interruption-level": "time-sensitive",
+			"relevance-score": 0.5,
+			"filter-criteria": "work",
+		},
+		"hedwig": { "version": 1 },
+	});
+
+	// Relayed through FCM, mentioning the user
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["apns"]["payload"], expected);
+
+	// Directly, without highlight
+	let mut expected = expected;
+	expected["aps"]["interruption-level"] = json!("active");
+	let payload: Value = serde_json::from_str(&apns_rx.recv().await.unwrap().to_json_string()?)?;
+	assert_eq!(payload["aps"], expected["aps"]);
+	assert_eq!(payload["hedwig"], expected["hedwig"]);
+	// The iOS device asked for the notification data as well
+	assert_eq!(payload["room_id"], "owo");
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn collapse_id_and_expiration() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.apns_headers.apns_collapse_id = Some("owo".to_owned());
+	settings.hedwig.apns_headers.apns_expiration = Some(3600);
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+	let request = json!({
+		"notification": {
+			"counts": { "unread": 2 },
+			"devices": [
+				get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm),
+				get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns),
+			],
+			"room_id": "owo",
+		}
+	});
+	let resp = run_request(&mut service, request).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["android"]["collapse_key"], "owo");
+	assert_eq!(message["android"]["ttl"], "3600s");
+	assert_eq!(message["apns"]["headers"]["apns-collapse-id"], "owo");
+	let expiration: u64 =
+		message["apns"]["headers"]["apns-expiration"].as_str().unwrap().parse()?;
+	assert!((now + 3600..now + 3660).contains(&expiration));
+
+	let options = apns_rx.recv().await.unwrap().options;
+	let expiration = options.apns_expiration.unwrap();
+	assert!((now + 3600..now + 3660).contains(&expiration));
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn direct_apns_notification_data() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut service = setup_server(
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let mut device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Apns);
+	device["data"] = json!({});
+	for (formatted_body, has_content) in [("<b>hi</b>".to_owned(), true), ("a".repeat(5000), false)]
+	{
+		let body = json!({
+			"notification": {
+				"counts": { "unread": 2 },
+				"devices": [device.clone()],
+				"event_id": "$uwu",
+				"room_id": "owo",
+				"sender": "@fox:example.org",
+				"content": { "msgtype": "m.text", "body": "hi", "formatted_body": formatted_body },
+				"ciphertext": "c1ph3r",
+				"ephemeral": "eph",
+				"mac": "m4c",
+			}
+		});
+		let resp = run_request(&mut service, body).await?;
+		assert_eq!(&resp, "{\"rejected\":[]}");
+
+		// The service extension gets everything it needs to fetch or decrypt the event
+		let json = apns_rx.recv().await.unwrap().to_json_string()?;
+		let payload: Value = serde_json::from_str(&json)?;
+		assert_eq!(payload["aps"]["alert"]["body"], "hi");
+		assert_eq!(payload["event_id"], "$uwu");
+		assert_eq!(payload["room_id"], "owo");
+		assert_eq!(payload["counts"], "{\"unread\":2,\"missed_calls\":null}");
+		assert_eq!(payload["ciphertext"], "c1ph3r");
+		assert_eq!(payload["ephemeral"], "eph");
+		assert_eq!(payload["mac"], "m4c");
+
+		// Keys that can be fetched are dropped until it fits within the size limit
+		assert_eq!(payload.get("content").is_some(), has_content);
+		assert!(payload.get("devices").is_some());
+		assert_eq!(payload["sender"], "@fox:example.org");
+		assert!(json.len() <= 4096);
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn android_notification_overrides() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.notification_android_overrides =
+		vec![settings::AndroidOverride::ChannelId, settings::AndroidOverride::Color];
+	let mut service = setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let mut device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	device["data"]["android_notification"] = json!({
+		"channel_id": "org.matrix.app.account2",
+		// Not allowed
+		"icon": "account2_icon",
+		// Invalid
+		"color": "red",
+	});
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1 },
+			"devices": [device],
+			"room_id": "owo",
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	let android_notification = &message["android"]["notification"];
+	assert_eq!(android_notification["channel_id"], "org.matrix.app.account2");
+	assert_eq!(android_notification["icon"], "notifications_icon");
+	assert!(android_notification.get("color").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn webpush() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.webpush = settings::Webpush {
+		ttl: Some(3600),
+		urgency: None,
+		icon: Some("https://app.example.org/icon.png".to_owned()),
+		badge: None,
+		link: Some("https://app.example.org/#/room/<room_id>".to_owned()),
+	};
+	let mut service = setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let mut device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	device["data"]["data_message"] = json!("web");
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1 },
+			"devices": [device],
+			"room_id": "!owo:example.org",
+			"prio": "low",
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["data"]["room_id"], "!owo:example.org");
+	assert_eq!(message["notification"]["title"], "🦊 1 🦊");
+	assert!(message.get("android").is_none());
+	assert!(message.get("apns").is_none());
+
+	let webpush = &message["webpush"];
+	assert_eq!(webpush["headers"], json!({ "TTL": "3600", "Urgency": "normal" }));
+	assert_eq!(
+		webpush["notification"],
+		json!({
+			"title": "🦊 1 🦊",
+			"body": "read the notification pls :c",
+			"icon": "https://app.example.org/icon.png",
+		})
+	);
+	assert_eq!(webpush["fcm_options"]["link"], "https://app.example.org/#/room/!owo:example.org");
+
+	// Badge only pushes don't link to any room
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 0 },
+			"devices": [device],
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert!(message["webpush"].get("fcm_options").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn notification_link() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.notification_link = Some("myapp://room/<room_id>/event/<event_id>".to_owned());
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let mut web_device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	web_device["data"]["data_message"] = json!("web");
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1 },
+			"devices": [
+				get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm),
+				get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Fcm),
+				get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Apns),
+				web_device,
+			],
+			"event_id": "$uwu",
+			"room_id": "!owo:example.org",
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+	let link = "myapp://room/!owo:example.org/event/$uwu";
+
+	// APNS target content id and custom key, Android keeps its click action
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["android"]["notification"]["click_action"], "FLUTTER_NOTIFICATION_CLICK");
+	assert_eq!(message["apns"]["payload"]["aps"]["target-content-id"], link);
+	assert_eq!(message["apns"]["payload"]["link"], link);
+
+	// FCM data
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["data"]["link"], link);
+
+	// Direct APNS
+	let payload: Value = serde_json::from_str(&apns_rx.recv().await.unwrap().to_json_string()?)?;
+	assert_eq!(payload["aps"]["target-content-id"], link);
+	assert_eq!(payload["link"], link);
+
+	// Webpush
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["webpush"]["fcm_options"]["link"], link);
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn notification_image() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let (apns_tx, mut apns_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.notification_image = Some(settings::NotificationImage {
+		media_proxy: "https://media.example.org/".to_owned(),
+		width: 96,
+		height: 96,
+		method: settings::ThumbnailMethod::Crop,
+	});
+	let mut service = setup_server_with_settings(
+		settings,
+		Box::new(FakeFcmSender(fcm_tx)),
+		Some(Box::new(FakeAPNSSender { tx: apns_tx })),
+	)?;
+
+	let mut fcm_device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	fcm_device["data"] = json!({});
+	let mut apns_device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Apns);
+	apns_device["data"] = json!({});
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1 },
+			"devices": [
+				fcm_device,
+				apns_device,
+				// event_id_only devices don't get any image
+				get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm),
+			],
+			"room_id": "owo",
+			"content": {
+				"msgtype": "m.image",
+				"body": "fox.png",
+				"url": "mxc://example.org/fox",
+				"info": { "thumbnail_url": "mxc://example.org/fox_thumb" },
+			},
+			"sender_avatar_url": "mxc://example.org/avatar",
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[]}");
+	let image = "https://media.example.org/_matrix/client/v1/media/thumbnail/example.org/fox_thumb?width=96&height=96&method=crop";
+
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["notification"]["image"], image);
+	assert_eq!(message["android"]["notification"]["image"], image);
+	assert_eq!(message["apns"]["payload"]["attachment_url"], image);
+	assert_eq!(message["apns"]["payload"]["aps"]["mutable-content"], 1);
+
+	let payload: Value = serde_json::from_str(&apns_rx.recv().await.unwrap().to_json_string()?)?;
+	assert_eq!(payload["attachment_url"], image);
+
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert!(message["notification"].get("image").is_none());
+	assert!(message["apns"]["payload"].get("attachment_url").is_none());
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_notification() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+	let mut settings = test_settings();
+	settings.hedwig.notification_link = Some("myapp://room/<room_id>/event/<event_id>".to_owned());
+	let mut service = setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let mut device = get_device("com.famedly.🦊", Platform::Generic, NotificationMethod::Fcm);
+	device["data"] = json!({
+		"algorithm": "com.famedly.curve25519-aes-sha2",
+		"public_key": "odb+sBwDVe1KJVKAO0wsrfc7gw4cJ7ydfBZADZ7nGDE",
+	});
+	let mut missing_key = device.clone();
+	missing_key["pushkey"] = json!("MissingKey");
+	missing_key["data"]["public_key"] = Value::Null;
+	let body = json!({
+		"notification": {
+			"counts": { "unread": 1 },
+			"devices": [device, missing_key],
+			"event_id": "$uwu",
+			"room_id": "!owo:example.org",
+			"type": "m.room.message",
+			"sender": "@fox:example.org",
+			"sender_display_name": "Fox",
+			"content": { "msgtype": "m.text", "body": "hello there" },
+		}
+	});
+	let resp = run_request(&mut service, body).await?;
+	assert_eq!(&resp, "{\"rejected\":[\"MissingKey\"]}");
+
+	// Neither the alert nor the data reveal the room or sender
+	let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+	assert_eq!(message["notification"]["title"], "🦊 1 🦊");
+	assert_eq!(message["notification"]["body"], "read the notification pls :c");
+	let data = &message["data"];
+	for key in ["event_id", "room_id", "type", "sender", "sender_display_name", "link"] {
+		assert!(data.get(key).is_none(), "{key} is not encrypted");
+	}
+	assert_eq!(message["android"]["notification"]["click_action"], "FLUTTER_NOTIFICATION_CLICK");
+	assert!(message["apns"]["payload"].get("link").is_none());
+	assert!(data.get("content").is_none());
+	for key in ["ciphertext", "ephemeral", "mac"] {
+		assert!(data[key].as_str().is_some_and(|v| !v.is_empty()));
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn provider_privacy() -> Result<(), Box<dyn std::error::Error>> {
+	for (privacy, keys) in [
+		(settings::ProviderPrivacy::Minimal, vec!["counts", "event_id", "room_id"]),
+		(
+			settings::ProviderPrivacy::Standard,
+			vec!["counts", "devices", "event_id", "prio", "room_id", "type"],
+		),
+	] {
+		let (fcm_tx, mut fcm_rx) = mpsc::channel(1337);
+		let mut settings = test_settings();
+		settings.hedwig.provider_privacy = privacy;
+		let mut service =
+			setup_server_with_settings(settings, Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+		let mut device = get_device("com.famedly.🦊", Platform::IoS, NotificationMethod::Fcm);
+		device["data"] = json!({ "data_message": "ios" });
+		device["tweaks"] = json!({ "sound": "default" });
+		let body = json!({
+			"notification": {
+				"counts": { "unread": 1 },
+				"devices": [device],
+				"event_id": "$uwu",
+				"room_id": "owo",
+				"type": "m.room.message",
+				"sender": "@fox:example.org",
+				"sender_display_name": "Fox",
+				"room_name": "Foxes",
+				"content": { "msgtype": "m.text", "body": "hello there" },
+			}
+		});
+		let resp = run_request(&mut service, body).await?;
+		assert_eq!(&resp, "{\"rejected\":[]}");
+
+		let message = serde_json::to_value(fcm_rx.recv().await.unwrap())?;
+		let data = message["data"].as_object().unwrap();
+		assert_eq!(data.keys().collect::<Vec<_>>(), keys, "{privacy:?}");
+		// The alert doesn't reveal more than the data
+		assert_eq!(message["notification"]["title"], "🦊 1 🦊");
+		assert_eq!(message["apns"]["payload"]["aps"]["alert"]["title"], "🦊 1 🦊");
+
+		if privacy == settings::ProviderPrivacy::Standard {
+			assert_eq!(
+				data["devices"],
+				"[{\"app_id\":\"com.famedly.🦊\",\"data\":{\"data_message\":\"ios\"},\"pushkey\":\"IoS\"}]"
+			);
+		}
+	}
+
+	Ok(())
+}
+
+#[tokio::test]
+async fn spec_error_responses() -> Result<(), Box<dyn std::error::Error>> {
+	let (fcm_tx, _fcm_rx) = mpsc::channel(1337);
+	let mut service = setup_server(Box::new(FakeFcmSender(fcm_tx)), None)?;
+
+	let valid = serde_json::to_string(&test_message(false, vec![]))?;
+	for (method, uri, content_type, body, status, errcode) in [
+		// Valid JSON not matching the schema
+		(
+			"POST",
+			"/_matrix/push/v1/notify",
+			Some("application/json"),
+			"{\"notification\":{}}".to_owned(),
+			StatusCode::BAD_REQUEST,
+			"M_BAD_JSON",
+		),
+		(
+			"POST",
+			"/_matrix/push/v1/notify",
+			Some("application/json"),
+			"{\"notification\":".to_owned(),
+			StatusCode::BAD_REQUEST,
+			"M_NOT_JSON",
+		),
+		("POST", "/_matrix/push/v1/notify", None, valid, StatusCode::BAD_REQUEST, "M_NOT_JSON"),
+		(
+			"GET",
+			"/_matrix/push/v1/notify",
+			None,
+			String::new(),
+			StatusCode::METHOD_NOT_ALLOWED,
+			"M_UNRECOGNIZED",
+		),
+		(
+			"POST",
+			"/_matrix/push/v1/unknown",
+			None,
+			String::new(),
+			StatusCode::NOT_FOUND,
+			"M_UNRECOGNIZED",
+		),
+	] {
+		let mut request = axum::http::Request::builder().method(method).uri(uri);
+		if let Some(content_type) = content_type {
+			request = request.header(CONTENT_TYPE, content_type);
+		}
+		let resp = service.call(request.body(Body::from(body))?).await?;
+
+		assert_eq!(resp.status(), status, "{method} {uri}");
+		let error: Value = serde_json::from_str(&response_to_string(resp).await?)?;
+		assert_eq!(error["errcode"], errcode, "{method} {uri}");
+		assert!(error["error"].as_str().is_some_and(|error| !error.is_empty()));
+	}
 
 	Ok(())
 }
@@ -10,3 +10,128 @@ fn load_settings() {
 	settings::Settings::load("tests/config-bad.yaml").unwrap_err();
 	settings::Settings::load("tests/config-bad-apns.yaml").unwrap_err();
 }
+
+#[test]
+fn settings_debug_redacted() {
+	let mut settings = settings::Settings::load("config.sample.yaml").unwrap();
+	settings.hedwig.apns_team_id = "TEAMID1234".to_owned();
+	settings.hedwig.apns_key_id = "KEYID12345".to_owned();
+
+	let debug = format!("{settings:?}");
+	assert!(!debug.contains("TEAMID1234"));
+	assert!(!debug.contains("KEYID12345"));
+	assert!(!debug.contains("YOUR_TEAM_ID"));
+	assert!(!debug.contains("YOUR_KEY_ID"));
+	assert_eq!(settings.log.privacy, settings::Privacy::Standard);
+}
+
+/// Sample settings that pass validation
+fn valid_settings() -> settings::Settings {
+	let mut settings = settings::Settings::load("config.sample.yaml").unwrap();
+	settings.hedwig.fcm_credentials_file_path = "tests/dummy-service-account.json".into();
+	settings.hedwig.apns_key_file_path = None;
+	settings.server.tls = None;
+	settings
+}
+
+#[test]
+fn validate_readiness_check_interval() {
+	valid_settings().validate().unwrap();
+
+	let mut settings = valid_settings();
+	settings.server.readiness_check_interval = 0;
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("server.readiness_check_interval"));
+}
+
+#[test]
+fn validate_tls_reload_interval() {
+	let mut settings = valid_settings();
+	settings.server.tls = Some(settings::Tls {
+		certificate_file_path: "tests/tls-cert.pem".into(),
+		key_file_path: "tests/tls-key.pem".into(),
+		client_ca_file_path: None,
+		client_auth_required: false,
+		reload_interval: settings::Tls::DEFAULT_RELOAD_INTERVAL,
+	});
+	settings.validate().unwrap();
+
+	settings.server.tls.as_mut().unwrap().reload_interval = 0;
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("server.tls.reload_interval"));
+}
+
+#[test]
+fn validate_apns_collapse_id() {
+	let mut settings = valid_settings();
+	settings.hedwig.apns_headers.apns_collapse_id = Some("a".repeat(64));
+	settings.validate().unwrap();
+
+	settings.hedwig.apns_headers.apns_collapse_id = Some("a".repeat(65));
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("apns_collapse_id"));
+}
+
+#[test]
+fn validate_admin_token() {
+	let mut settings = valid_settings();
+	settings.server.admin = Some(settings::Admin {
+		port: 4568,
+		bind_address: [127, 0, 0, 1].into(),
+		token: "sesame".to_owned(),
+	});
+	settings.validate().unwrap();
+
+	for token in ["", "  "] {
+		settings.server.admin.as_mut().unwrap().token = token.to_owned();
+		let err = settings.validate().unwrap_err();
+		assert!(err.to_string().contains("server.admin.token"));
+	}
+}
+
+#[test]
+fn validate_homeserver_access() {
+	let mut settings = valid_settings();
+	settings.server.access.homeservers = vec![settings::HomeserverAccess {
+		name: "matrix.example.org".to_owned(),
+		token: Some("hunter2".to_owned()),
+		client_certificate_fingerprints: Vec::new(),
+		trusted: false,
+	}];
+	settings.validate().unwrap();
+
+	settings.server.access.homeservers[0].token = Some(String::new());
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("must not be empty"));
+
+	settings.server.access.homeservers[0].token = None;
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("needs a token or client certificate fingerprints"));
+
+	settings.server.access.homeservers[0].client_certificate_fingerprints =
+		vec!["0f93d472da75d2dc69f3bc7386da6c1118191c9d812e49a278b1b3463dc02376".to_owned()];
+	settings.validate().unwrap();
+}
+
+#[test]
+fn validate_apns_payload() {
+	let mut settings = valid_settings();
+	for score in [0.0, 0.5, 1.0] {
+		settings.hedwig.apns_payload.relevance_score = Some(score);
+		settings.validate().unwrap();
+	}
+
+	for score in [-0.1, 1.5, f64::NAN] {
+		settings.hedwig.apns_payload.relevance_score = Some(score);
+		let err = settings.validate().unwrap_err();
+		assert!(err.to_string().contains("relevance_score"));
+	}
+
+	let mut settings = valid_settings();
+	settings.hedwig.apns_payload.custom.insert("app".to_owned(), serde_json::json!("hi"));
+	settings.validate().unwrap();
+
+	settings.hedwig.apns_payload.custom.insert("aps".to_owned(), serde_json::json!({}));
+	let err = settings.validate().unwrap_err();
+	assert!(err.to_string().contains("apns_payload.custom"));
+}
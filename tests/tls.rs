@@ -0,0 +1,276 @@
+/*
+ *   Matrix Hedwig
+ *   Copyright (C) 2019, 2020, 2021, 2022 Famedly GmbH
+ *
+ *   This program is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU Affero General Public License as
+ *   published by the Free Software Foundation, either version 3 of the
+ *   License, or (at your option) any later version.
+ *
+ *   This program is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *   GNU Affero General Public License for more details.
+ *
+ *   You should have received a copy of the GNU Affero General Public License
+ *   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tests for the TLS listener configuration.
+
+#![allow(clippy::unwrap_used)]
+
+use std::{
+	net::SocketAddr,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use firebae_cm::MessageBody;
+use matrix_hedwig::{
+	api::{create_router, AppState},
+	error::HedwigError,
+	fcm::FcmSender,
+	models::Metrics,
+	settings::{HomeserverAccess, Settings, Tls},
+	tls::{server_config, watch_certificates, ClientCertificateAcceptor},
+};
+use rustls::{
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+	pki_types::{CertificateDer, ServerName, UnixTime},
+	ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+fn tls_settings(client_ca_file_path: Option<&str>) -> Tls {
+	Tls {
+		certificate_file_path: PathBuf::from("tests/tls-cert.pem"),
+		key_file_path: PathBuf::from("tests/tls-key.pem"),
+		client_ca_file_path: client_ca_file_path.map(PathBuf::from),
+		client_auth_required: false,
+		reload_interval: Tls::DEFAULT_RELOAD_INTERVAL,
+	}
+}
+
+#[test]
+fn tls_server_config() {
+	let config = server_config(&tls_settings(None));
+	assert!(config.is_ok());
+}
+
+#[test]
+fn tls_server_config_with_client_ca() {
+	let config = server_config(&tls_settings(Some("tests/tls-cert.pem")));
+	assert!(config.is_ok());
+}
+
+#[test]
+fn tls_server_config_missing_files() {
+	let mut settings = tls_settings(None);
+	settings.key_file_path = PathBuf::from("nonexistent.pem");
+	assert!(server_config(&settings).is_err());
+
+	// The APNs key is not a PEM certificate
+	let settings = tls_settings(Some("tests/test.key"));
+	assert!(server_config(&settings).is_err());
+}
+
+/// Accepts any server certificate, the tests only check which one is served
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+/// Client configuration trusting any server certificate
+fn client_config() -> rustls::ConfigBuilder<ClientConfig, rustls::client::WantsClientCert> {
+	let provider = Arc::new(aws_lc_rs::default_provider());
+	ClientConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+}
+
+/// The certificate the server at the given address presents
+async fn served_certificate(addr: SocketAddr) -> CertificateDer<'static> {
+	let config = client_config().with_no_client_auth();
+
+	let stream = TcpStream::connect(addr).await.unwrap();
+	let stream = TlsConnector::from(Arc::new(config))
+		.connect(ServerName::try_from("localhost").unwrap(), stream)
+		.await
+		.unwrap();
+	stream.get_ref().1.peer_certificates().unwrap()[0].clone().into_owned()
+}
+
+/// The first certificate in a PEM file
+fn certificate(path: &str) -> CertificateDer<'static> {
+	let pem = std::fs::read(path).unwrap();
+	rustls_pemfile::certs(&mut pem.as_slice()).next().unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn tls_certificate_hot_reload() {
+	let dir = std::env::temp_dir().join(format!("hedwig-tls-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::copy("tests/tls-cert.pem", dir.join("cert.pem")).unwrap();
+	std::fs::copy("tests/tls-key.pem", dir.join("key.pem")).unwrap();
+
+	let tls = Tls {
+		certificate_file_path: dir.join("cert.pem"),
+		key_file_path: dir.join("key.pem"),
+		client_ca_file_path: None,
+		client_auth_required: false,
+		reload_interval: 1,
+	};
+	let config = RustlsConfig::from_config(Arc::new(server_config(&tls).unwrap()));
+	watch_certificates(&tls, config.clone());
+
+	let handle = axum_server::Handle::new();
+	tokio::spawn(
+		axum_server::bind(([127, 0, 0, 1], 0).into())
+			.acceptor(ClientCertificateAcceptor::new(config))
+			.handle(handle.clone())
+			.serve(Router::new().into_make_service()),
+	);
+	let addr = handle.listening().await.unwrap();
+	assert_eq!(served_certificate(addr).await, certificate("tests/tls-cert.pem"));
+
+	// A half swapped pair fails to load and is picked up on the next change
+	std::fs::copy("tests/tls-key-2.pem", dir.join("key.pem")).unwrap();
+	std::fs::copy("tests/tls-cert-2.pem", dir.join("cert.pem")).unwrap();
+
+	let new_certificate = certificate("tests/tls-cert-2.pem");
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while served_certificate(addr).await != new_certificate {
+		assert!(Instant::now() < deadline, "The new certificate was never served");
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+
+	handle.shutdown();
+	std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Never sends anything, the mutual TLS tests push to no devices
+#[derive(Debug)]
+struct NoFcmSender;
+#[async_trait]
+impl FcmSender for NoFcmSender {
+	async fn send(&self, _message: MessageBody) -> Result<String, HedwigError> {
+		unreachable!("No devices are pushed to")
+	}
+}
+
+/// Serves the push gateway over mutual TLS, only allowing the homeserver
+/// with the given client certificate fingerprint
+async fn serve_mutual_tls(fingerprint: &str) -> (SocketAddr, axum_server::Handle) {
+	let mut settings = Settings::load("config.sample.yaml").unwrap();
+	settings.server.access.homeservers = vec![HomeserverAccess {
+		name: "matrix.example.org".to_owned(),
+		token: None,
+		client_certificate_fingerprints: vec![fingerprint.to_owned()],
+		trusted: false,
+	}];
+	let app_state = AppState::new(
+		Box::new(NoFcmSender),
+		None,
+		settings,
+		Metrics::new(&opentelemetry::global::meter("Hedwig")),
+	);
+	let router = create_router(app_state, Arc::new(prometheus::Registry::new())).unwrap();
+
+	let config = server_config(&tls_settings(Some("tests/tls-client-ca.pem"))).unwrap();
+	let handle = axum_server::Handle::new();
+	tokio::spawn(
+		axum_server::bind(([127, 0, 0, 1], 0).into())
+			.acceptor(ClientCertificateAcceptor::new(RustlsConfig::from_config(Arc::new(config))))
+			.handle(handle.clone())
+			.serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+	);
+	(handle.listening().await.unwrap(), handle)
+}
+
+/// Status code of a notify request to the given address, sent with the test
+/// client certificate
+async fn notify_with_client_certificate(addr: SocketAddr) -> u16 {
+	let key = std::fs::read("tests/tls-client-key.pem").unwrap();
+	let key = rustls_pemfile::private_key(&mut key.as_slice()).unwrap().unwrap();
+	let config = client_config()
+		.with_client_auth_cert(vec![certificate("tests/tls-client-cert.pem")], key)
+		.unwrap();
+
+	let stream = TcpStream::connect(addr).await.unwrap();
+	let mut stream = TlsConnector::from(Arc::new(config))
+		.connect(ServerName::try_from("localhost").unwrap(), stream)
+		.await
+		.unwrap();
+
+	let body = r#"{"notification":{"counts":{"unread":1},"devices":[]}}"#;
+	let request = format!(
+		"POST /_matrix/push/v1/notify HTTP/1.1\r\nHost: localhost\r\nContent-Type: \
+		 application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+		body.len()
+	);
+	stream.write_all(request.as_bytes()).await.unwrap();
+
+	// "HTTP/1.1 200"
+	let mut status_line = [0; 12];
+	stream.read_exact(&mut status_line).await.unwrap();
+	std::str::from_utf8(&status_line[9..]).unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn mutual_tls_access_control() {
+	// SHA-256 fingerprint of tests/tls-client-cert.pem, in the colon separated
+	// form tools like openssl print
+	let fingerprint = "0F:93:D4:72:DA:75:D2:DC:69:F3:BC:73:86:DA:6C:11:18:19:1C:9D:81:2E:49:A2:78:\
+	                   B1:B3:46:3D:C0:23:76";
+	let (addr, handle) = serve_mutual_tls(fingerprint).await;
+	assert_eq!(notify_with_client_certificate(addr).await, 200);
+	handle.shutdown();
+
+	let (addr, handle) = serve_mutual_tls(&"00".repeat(32)).await;
+	assert_eq!(notify_with_client_certificate(addr).await, 403);
+	handle.shutdown();
+}